@@ -0,0 +1,96 @@
+use core::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use crate::opam_version::OpamVersion;
+
+/// What went wrong while reading or encoding a package version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A file or directory in the repository couldn't be read.
+    Io(String),
+    /// There is no such package in the repository.
+    MissingPackage,
+    /// An `opam` or `opam.json` file couldn't be parsed.
+    Syntax(String),
+    /// A comparison we can't encode, e.g. between two package variables.
+    UnsupportedComparison(String),
+    /// A literal where a formula was expected.
+    InvalidLiteral(String),
+    /// A pseudo-version that the synthetic package doesn't have.
+    UnknownVersion(String),
+    /// A formula that can't appear where it was found.
+    UnexpectedFormula(String),
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::Io(msg) => write!(f, "{}", msg),
+            ErrorKind::MissingPackage => write!(f, "package not found in repository"),
+            ErrorKind::Syntax(msg) => write!(f, "syntax error: {}", msg),
+            ErrorKind::UnsupportedComparison(formula) => write!(f, "unsupported comparison: {}", formula),
+            ErrorKind::InvalidLiteral(formula) => write!(f, "invalid literal: {}", formula),
+            ErrorKind::UnknownVersion(version) => write!(f, "unknown version {}", version),
+            ErrorKind::UnexpectedFormula(formula) => write!(f, "unexpected formula: {}", formula),
+        }
+    }
+}
+
+/// An error for a package version, with as much context as is known where it occurred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub package: Option<String>,
+    pub version: Option<OpamVersion>,
+    pub path: Option<PathBuf>,
+    pub kind: ErrorKind,
+}
+
+impl Error {
+    pub fn with_package(mut self, package: &str, version: &OpamVersion) -> Self {
+        self.package.get_or_insert_with(|| package.to_string());
+        self.version.get_or_insert_with(|| version.clone());
+        self
+    }
+
+    pub fn with_path(mut self, path: &Path) -> Self {
+        self.path.get_or_insert_with(|| path.to_path_buf());
+        self
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self {
+            package: None,
+            version: None,
+            path: None,
+            kind,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.package, &self.version) {
+            (Some(package), Some(version)) => write!(f, "{}.{}: ", package, version)?,
+            (Some(package), None) => write!(f, "{}: ", package)?,
+            _ => (),
+        }
+        write!(f, "{}", self.kind)?;
+        if let Some(path) = &self.path {
+            write!(f, " ({})", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// What `Index` does when a package version can't be read or encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Abort the solve with the error.
+    Fail,
+    /// Treat the offending version as unavailable, with the error as the reason.
+    Unavailable,
+}
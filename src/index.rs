@@ -1,17 +1,332 @@
 use core::fmt::Display;
-use pubgrub::Range;
-use std::cell::Cell;
+use std::cell::RefCell;
+use pubgrub::{DerivationTree, External, Map, PubGrubError, Range, SelectedDependencies, Set};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::hash::{Hash, Hasher};
 
+use crate::error::{Error, ErrorKind, ErrorPolicy};
 use crate::opam_version::OpamVersion;
-use crate::parse::{available_versions_from_repo, RelOp};
+use crate::opam_deps::{
+    installed_range, Package, FALSE_VERSION, NOT_INSTALLED_VERSION, TRUE_VERSION, UNDEFINED_VERSION,
+};
+use crate::parse::{
+    and_version_formula, available_versions_from_repo, or_version_formula, packages_from_repo,
+    parse_package_version, read_profile, relop_to_range, OpamFile, RelOp,
+};
+use crate::platform::host_variables;
 
 pub type PackageName = String;
 
+/// The flags of each version of a package, e.g. `avoid-version`.
+pub type VersionFlags = BTreeMap<OpamVersion, Vec<String>>;
+
+/// Something read from the repository, or why it couldn't be read.
+type Cached<T> = Result<Arc<T>, Error>;
+
+/// Repository data that doesn't change during a solve, shared between the indexes
+/// of concurrent solves.
+pub struct Repository {
+    pub path: String,
+    packages: OnceLock<Result<Vec<PackageName>, Error>>,
+    versions: Mutex<BTreeMap<PackageName, Cached<Vec<OpamVersion>>>>,
+    conflict_classes: OnceLock<Result<BTreeMap<String, BTreeSet<OpamVersion>>, Error>>,
+    flags: Mutex<BTreeMap<PackageName, Cached<VersionFlags>>>,
+    variable_values: OnceLock<Result<BTreeMap<String, BTreeSet<OpamVersion>>, Error>>,
+    opam_files: Mutex<BTreeMap<(PackageName, OpamVersion), Cached<OpamFile>>>,
+}
+
+impl Repository {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            packages: OnceLock::new(),
+            versions: Mutex::new(BTreeMap::new()),
+            conflict_classes: OnceLock::new(),
+            flags: Mutex::new(BTreeMap::new()),
+            variable_values: OnceLock::new(),
+            opam_files: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The opam file of a package version, parsed the first time it's asked for.
+    pub fn opam_file(&self, package: &str, version: &OpamVersion) -> Cached<OpamFile> {
+        let key = (package.to_string(), version.clone());
+        if let Some(file) = self.opam_files.lock().unwrap().get(&key) {
+            return file.clone();
+        }
+        let file = parse_package_version(self.path.as_str(), package, &version.to_string())
+            .map(Arc::new)
+            .map_err(|err| err.with_package(package, version));
+        self.opam_files.lock().unwrap().insert(key, file.clone());
+        file
+    }
+
+    /// The names of all packages in the repository.
+    pub fn packages(&self) -> Result<&[PackageName], Error> {
+        match self.packages.get_or_init(|| packages_from_repo(self.path.as_str())) {
+            Ok(packages) => Ok(packages),
+            Err(err) => Err(err.clone()),
+        }
+    }
+
+    /// The versions of a package in the repository, newest first, read the first time
+    /// they're asked for.
+    pub fn versions(&self, package: &str) -> Cached<Vec<OpamVersion>> {
+        if let Some(versions) = self.versions.lock().unwrap().get(package) {
+            return versions.clone();
+        }
+        let versions = available_versions_from_repo(self.path.as_str(), package).map(Arc::new);
+        self.versions
+            .lock()
+            .unwrap()
+            .insert(package.to_string(), versions.clone());
+        versions
+    }
+
+    /// The packages in each conflict class, found by scanning every package version in
+    /// the repository the first time it's asked for. A version that can't be read is
+    /// never installed, so whatever the error policy it doesn't make its package a
+    /// member; the policy applies if the solver picks that version.
+    pub fn conflict_classes(&self) -> Result<&BTreeMap<String, BTreeSet<OpamVersion>>, Error> {
+        let classes = self.conflict_classes.get_or_init(|| {
+            let mut classes: BTreeMap<String, BTreeSet<OpamVersion>> = BTreeMap::new();
+            for package in self.packages()? {
+                // a package whose versions can't be listed has none to install
+                for version in self.versions(package).iter().flat_map(|versions| versions.iter()) {
+                    if let Ok(file) = self.opam_file(package, version) {
+                        for class in &file.conflict_classes {
+                            classes
+                                .entry(class.clone())
+                                .or_default()
+                                .insert(OpamVersion(package.clone()));
+                        }
+                    }
+                }
+            }
+            Ok(classes)
+        });
+        match classes {
+            Ok(classes) => Ok(classes),
+            Err(err) => Err(err.clone()),
+        }
+    }
+
+    /// The values each variable is compared against anywhere in the repository, with
+    /// the undefined value for those tested with `?`, found by scanning every package
+    /// version the first time they're asked for. Versions that can't be read are
+    /// skipped, whatever the error policy, as the solver never picks them.
+    pub fn variable_values(&self) -> Result<&BTreeMap<String, BTreeSet<OpamVersion>>, Error> {
+        let values = self.variable_values.get_or_init(|| {
+            let mut values = BTreeMap::new();
+            for package in self.packages()? {
+                for version in self.versions(package).iter().flat_map(|versions| versions.iter()) {
+                    if let Ok(file) = self.opam_file(package, version) {
+                        for formula in &file.dependencies {
+                            formula.collect_variable_values(&mut values);
+                        }
+                        if let Some(available) = &file.available {
+                            available.collect_variable_values(&mut values);
+                        }
+                    }
+                }
+            }
+            Ok(values)
+        });
+        match values {
+            Ok(values) => Ok(values),
+            Err(err) => Err(err.clone()),
+        }
+    }
+
+    /// The flags of the versions of a package that have any, read the first time
+    /// they're asked for. A version that can't be read has none, so it's ordered like
+    /// any other, and the error policy applies if the solver picks it.
+    pub fn flags(&self, package: &str) -> Cached<VersionFlags> {
+        if let Some(flags) = self.flags.lock().unwrap().get(package) {
+            return flags.clone();
+        }
+        let flags = match self.versions(package) {
+            Ok(versions) => Ok(versions
+                .iter()
+                .filter_map(|version| {
+                    let file = self.opam_file(package, version).ok()?;
+                    (!file.flags.is_empty()).then(|| (version.clone(), file.flags.clone()))
+                })
+                .collect()),
+            // a package that doesn't exist has no versions to flag
+            Err(Error {
+                kind: ErrorKind::MissingPackage,
+                ..
+            }) => Ok(BTreeMap::new()),
+            Err(err) => Err(err),
+        }
+        .map(Arc::new);
+        self.flags
+            .lock()
+            .unwrap()
+            .insert(package.to_string(), flags.clone());
+        flags
+    }
+}
+
+/// How the variables that opam gives a value per package are decided.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum VariablePolicy {
+    /// Like any other variable, from the environment or else by the solver.
+    #[default]
+    Free,
+    /// As `opam install` does: `build` and `post` are true, `with-test` and `with-doc`
+    /// are true only for the given (usually the requested) packages, and `dev` is true
+    /// only for development versions.
+    Opam {
+        with_test: BTreeSet<PackageName>,
+        with_doc: BTreeSet<PackageName>,
+    },
+}
+
+/// Which of the available versions of a package the solver tries first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum VersionStrategy {
+    /// The newest version.
+    #[default]
+    Newest,
+    /// The oldest version, to check that declared lower bounds are enough.
+    Oldest,
+    /// The locked (e.g. installed) version of each package, then the newest, except
+    /// for the packages to upgrade, which may only move to their locked version or a
+    /// newer one. Locked versions of the other packages are only preferred, so they
+    /// still change when the packages to upgrade need them to.
+    Locked {
+        versions: BTreeMap<PackageName, OpamVersion>,
+        upgrade: BTreeSet<PackageName>,
+    },
+}
+
+/// The priority of a package for PubGrub to decide, highest first: the number of
+/// conflicts it has been involved in, the number of versions left in range (fewer is
+/// higher), and whether it's of the kind to decide first.
+pub type Priority = (u32, Reverse<usize>, bool);
+
+/// A prioritizer of the caller's own, given a package, the number of its versions left
+/// in range and the number of conflicts it has been involved in.
+pub type PrioritizeFn = dyn Fn(&Package, usize, u32) -> Priority + Send + Sync;
+
+/// How `Index` orders the packages for PubGrub to decide, most urgent first.
+///
+/// `Constant` is the default, as it made the fewest decisions and was fastest in
+/// `bench_prioritizers`, which compares them on a generated repository.
+#[derive(Clone, Default)]
+pub enum Prioritizer {
+    /// Every package alike, so they're decided in the order PubGrub finds them.
+    #[default]
+    Constant,
+    /// Packages that have been involved in the most conflicts first, then those with
+    /// the fewest versions left in range, then real packages and variables before the
+    /// synthetic packages that encode formulas, or after them with `synthetic_first`.
+    Conflicts { synthetic_first: bool },
+    /// The priority a function of the caller's own gives each package.
+    Custom(Arc<PrioritizeFn>),
+}
+
+impl std::fmt::Debug for Prioritizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Prioritizer::Constant => write!(f, "Constant"),
+            Prioritizer::Conflicts { synthetic_first } => f
+                .debug_struct("Conflicts")
+                .field("synthetic_first", synthetic_first)
+                .finish(),
+            Prioritizer::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+impl PartialEq for Prioritizer {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Prioritizer::Constant, Prioritizer::Constant) => true,
+            (
+                Prioritizer::Conflicts { synthetic_first: lhs },
+                Prioritizer::Conflicts { synthetic_first: rhs },
+            ) => lhs == rhs,
+            (Prioritizer::Custom(lhs), Prioritizer::Custom(rhs)) => Arc::ptr_eq(lhs, rhs),
+            _ => false,
+        }
+    }
+}
+
+/// Splits a package variable such as `ocaml:version` into the package and the variable.
+pub fn split_package_variable(var: &str) -> Option<(&str, &str)> {
+    var.split_once(':')
+}
+
+/// Whether a version is a development version. We don't model pins, so this is a
+/// version with a `dev` component, e.g. `dev` or `1.0~dev`.
+pub fn is_dev_version(version: &OpamVersion) -> bool {
+    version
+        .to_string()
+        .split(['.', '~', '+', '-', '_'])
+        .any(|component| component == "dev")
+}
+
+/// The repository and the settings to solve with. An index doesn't change while solving,
+/// so one index can be shared by concurrent solves, each with its own `Solver`.
+#[derive(Clone)]
 pub struct Index {
-    pub repo: String,
-    pub debug: Cell<bool>,
-    pub version_debug: Cell<bool>,
+    pub(crate) repository: Arc<Repository>,
+    pub(crate) debug: bool,
+    pub(crate) version_debug: bool,
+    pub(crate) error_policy: ErrorPolicy,
+    variable_policy: VariablePolicy,
+    pub(crate) prioritizer: Prioritizer,
+    version_strategy: VersionStrategy,
+    /// Variables fixed by the caller, e.g. `os` and `arch`; any others are chosen by the
+    /// solver.
+    environment: BTreeMap<String, OpamVersion>,
+    /// The values each variable may take, in order of preference, for variables the
+    /// solver chooses.
+    domains: BTreeMap<String, Vec<OpamVersion>>,
+    /// The values of package variables such as `ocaml:native`, by package and variable,
+    /// for each version of the package.
+    package_variables: BTreeMap<(PackageName, String), BTreeMap<OpamVersion, OpamVersion>>,
+    /// The variables that may be undefined: those declared so, including those that
+    /// `scan_domains` finds tested with `?`. These are known before solving, so that
+    /// the encoding doesn't depend on the order the solver visits packages in.
+    undefined: BTreeSet<String>,
+}
+
+/// A single solve over an index, with the state discovered while solving. This is the
+/// `DependencyProvider` that PubGrub solves with.
+pub struct Solver {
+    pub(crate) index: Index,
+    pub(crate) missing: RefCell<BTreeSet<PackageName>>,
+    /// The values seen compared against each variable, which become its versions.
+    pub(crate) variables: RefCell<BTreeMap<String, BTreeSet<OpamVersion>>>,
+    /// The dependencies of package versions that only their conflicts constrain.
+    pub(crate) conflicts: RefCell<Set<(Package, OpamVersion, Package)>>,
+    /// The package versions that each synthetic package was seen a dependency of.
+    pub(crate) parents: RefCell<Map<Package, Set<(Package, OpamVersion)>>>,
+}
+
+/// The versions selected by a solve: the installed packages, and the variables and
+/// synthetic packages the solve decided.
+pub type Solution = SelectedDependencies<Solver>;
+
+/// A solution without the packages selected at `NOT_INSTALLED_VERSION`, which those
+/// that are conflicted with or optionally depended on are when they aren't installed.
+/// `Solver::solve` leaves them out already; this is for the result of calling
+/// `pubgrub::resolve` with a `Solver` directly.
+pub fn installed(solution: &Solution) -> Solution {
+    solution
+        .iter()
+        .filter(|(package, version)| {
+            !matches!(package, Package::Base(_)) || **version != *NOT_INSTALLED_VERSION
+        })
+        .map(|(package, version)| (package.clone(), version.clone()))
+        .collect()
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -42,12 +357,22 @@ pub enum VersionFormula {
     Lit(OpamVersion),
     Variable(String),
     Not(String),
+    /// The variable has a value, i.e. `?var`.
+    Defined(String),
+    /// The variable has no value, i.e. `!?var`.
+    Undefined(String),
     And(Binary<VersionFormula>),
     Or(Binary<VersionFormula>),
     Comparator {
         relop: RelOp,
         binary: Binary<VersionFormula>,
     },
+    /// A constraint on the version of the dependency by the value of a filter, e.g.
+    /// `>= version`, which becomes a `Version` once the value is known.
+    Constraint {
+        relop: RelOp,
+        arg: Box<VersionFormula>,
+    },
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -62,6 +387,14 @@ pub enum PackageFormula {
         name: PackageName,
         package: PackageName,
     },
+    Conflict {
+        name: PackageName,
+        formula: VersionFormula,
+    },
+    Depopt {
+        name: PackageName,
+        formula: VersionFormula,
+    },
 }
 
 impl Display for RelOp {
@@ -86,6 +419,12 @@ impl Display for VersionFormula {
             VersionFormula::Not(variable) => {
                 write!(f, "!{}", variable)
             }
+            VersionFormula::Defined(variable) => {
+                write!(f, "?{}", variable)
+            }
+            VersionFormula::Undefined(variable) => {
+                write!(f, "!?{}", variable)
+            }
             VersionFormula::Lit(literal) => {
                 write!(f, "{}", literal)
             }
@@ -102,6 +441,9 @@ impl Display for VersionFormula {
                 // infix notation
                 write!(f, "({} {} {})", binary.lhs, relop, binary.rhs)
             }
+            VersionFormula::Constraint { relop, arg } => {
+                write!(f, "{} {}", relop, arg)
+            }
         }
     }
 }
@@ -115,6 +457,12 @@ impl Display for PackageFormula {
             PackageFormula::ConflictClass { name, package } => {
                 write!(f, "(Conflict class ({}, {}) )", name, package)
             }
+            PackageFormula::Conflict { name, formula } => {
+                write!(f, "(conflicts {} {{{}}})", name, formula)
+            }
+            PackageFormula::Depopt { name, formula } => {
+                write!(f, "(depopt {} {{{}}})", name, formula)
+            }
             PackageFormula::And(binary) => {
                 write!(f, "({} & {})", binary.lhs, binary.rhs)
             }
@@ -125,24 +473,640 @@ impl Display for PackageFormula {
     }
 }
 
+/// A formula with some of its variables replaced by their values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Substituted {
+    Const(bool),
+    Formula(VersionFormula),
+}
+
+impl VersionFormula {
+    /// Replaces the variables `value` knows with their values, simplifying the formula
+    /// as far as that allows.
+    pub fn substitute(&self, value: &impl Fn(&str) -> Option<OpamVersion>) -> Substituted {
+        let resolve = |formula: &VersionFormula| match formula {
+            VersionFormula::Lit(lit) => Some(lit.clone()),
+            VersionFormula::Variable(var) => value(var),
+            _ => None,
+        };
+        match self {
+            VersionFormula::Variable(var) => match value(var) {
+                Some(v) => Substituted::Const(v == *TRUE_VERSION),
+                None => Substituted::Formula(self.clone()),
+            },
+            VersionFormula::Not(var) => match value(var) {
+                Some(v) => Substituted::Const(v == *FALSE_VERSION),
+                None => Substituted::Formula(self.clone()),
+            },
+            VersionFormula::Defined(var) | VersionFormula::Undefined(var) => match value(var) {
+                Some(_) => Substituted::Const(matches!(self, VersionFormula::Defined(_))),
+                None => Substituted::Formula(self.clone()),
+            },
+            VersionFormula::Lit(lit) if *lit == *TRUE_VERSION => Substituted::Const(true),
+            VersionFormula::Lit(lit) if *lit == *FALSE_VERSION => Substituted::Const(false),
+            VersionFormula::And(Binary { lhs, rhs }) => {
+                match (lhs.substitute(value), rhs.substitute(value)) {
+                    (Substituted::Const(false), _) | (_, Substituted::Const(false)) => {
+                        Substituted::Const(false)
+                    }
+                    (Substituted::Const(true), other) | (other, Substituted::Const(true)) => other,
+                    (Substituted::Formula(lhs), Substituted::Formula(rhs)) => {
+                        Substituted::Formula(and_version_formula(lhs, rhs))
+                    }
+                }
+            }
+            VersionFormula::Or(Binary { lhs, rhs }) => {
+                match (lhs.substitute(value), rhs.substitute(value)) {
+                    (Substituted::Const(true), _) | (_, Substituted::Const(true)) => {
+                        Substituted::Const(true)
+                    }
+                    (Substituted::Const(false), other) | (other, Substituted::Const(false)) => other,
+                    (Substituted::Formula(lhs), Substituted::Formula(rhs)) => {
+                        Substituted::Formula(or_version_formula(lhs, rhs))
+                    }
+                }
+            }
+            VersionFormula::Comparator { relop, binary } => {
+                match (resolve(&binary.lhs), resolve(&binary.rhs)) {
+                    (Some(lhs), Some(rhs)) => {
+                        Substituted::Const(relop_to_range(relop, rhs).contains(&lhs))
+                    }
+                    // keep what we know for the solver
+                    (lhs, rhs) => Substituted::Formula(VersionFormula::Comparator {
+                        relop: relop.clone(),
+                        binary: Binary {
+                            lhs: Box::new(lhs.map_or(*binary.lhs.clone(), VersionFormula::Lit)),
+                            rhs: Box::new(rhs.map_or(*binary.rhs.clone(), VersionFormula::Lit)),
+                        },
+                    }),
+                }
+            }
+            VersionFormula::Constraint { relop, arg } => match resolve(arg) {
+                Some(v) => Substituted::Formula(VersionFormula::Version(HashedRange(
+                    relop_to_range(relop, v),
+                ))),
+                None => Substituted::Formula(self.clone()),
+            },
+            _ => Substituted::Formula(self.clone()),
+        }
+    }
+
+    /// Adds the values each variable is compared against in the formula, with the
+    /// undefined value for those tested with `?`.
+    pub fn collect_variable_values(&self, found: &mut BTreeMap<String, BTreeSet<OpamVersion>>) {
+        match self {
+            VersionFormula::And(binary) | VersionFormula::Or(binary) => {
+                binary.lhs.collect_variable_values(found);
+                binary.rhs.collect_variable_values(found);
+            }
+            VersionFormula::Comparator { relop: _, binary } => {
+                match (&*binary.lhs, &*binary.rhs) {
+                    (VersionFormula::Variable(var), VersionFormula::Lit(value))
+                    | (VersionFormula::Lit(value), VersionFormula::Variable(var))
+                        if split_package_variable(var).is_none() =>
+                    {
+                        found.entry(var.clone()).or_default().insert(value.clone());
+                    }
+                    _ => {
+                        binary.lhs.collect_variable_values(found);
+                        binary.rhs.collect_variable_values(found);
+                    }
+                }
+            }
+            VersionFormula::Defined(var) | VersionFormula::Undefined(var)
+                if split_package_variable(var).is_none() =>
+            {
+                found.entry(var.clone()).or_default().insert(UNDEFINED_VERSION.clone());
+            }
+            _ => (),
+        }
+    }
+}
+
+impl PackageFormula {
+    /// Whether the formula only comes from `conflicts`, so that it constrains packages
+    /// without depending on them.
+    pub fn is_conflict(&self) -> bool {
+        match self {
+            PackageFormula::Conflict { .. } => true,
+            PackageFormula::And(Binary { lhs, rhs }) | PackageFormula::Or(Binary { lhs, rhs }) => {
+                lhs.is_conflict() && rhs.is_conflict()
+            }
+            _ => false,
+        }
+    }
+
+    /// Replaces the variables `value` knows with their values in the filters of the
+    /// formula, dropping the dependencies whose filters become false as opam does.
+    /// Returns `None` if nothing is left.
+    pub fn substitute(&self, value: &impl Fn(&str) -> Option<OpamVersion>) -> Option<PackageFormula> {
+        let filtered = |formula: &VersionFormula| match formula.substitute(value) {
+            Substituted::Const(false) => None,
+            Substituted::Const(true) => Some(VersionFormula::Version(HashedRange(Range::full()))),
+            Substituted::Formula(formula) => Some(formula),
+        };
+        match self {
+            PackageFormula::And(Binary { lhs, rhs }) => {
+                match (lhs.substitute(value), rhs.substitute(value)) {
+                    (Some(lhs), Some(rhs)) => Some(PackageFormula::And(Binary {
+                        lhs: Box::new(lhs),
+                        rhs: Box::new(rhs),
+                    })),
+                    (lhs, rhs) => lhs.or(rhs),
+                }
+            }
+            PackageFormula::Or(Binary { lhs, rhs }) => {
+                match (lhs.substitute(value), rhs.substitute(value)) {
+                    (Some(lhs), Some(rhs)) => Some(PackageFormula::Or(Binary {
+                        lhs: Box::new(lhs),
+                        rhs: Box::new(rhs),
+                    })),
+                    (lhs, rhs) => lhs.or(rhs),
+                }
+            }
+            PackageFormula::Base { name, formula } => filtered(formula).map(|formula| {
+                PackageFormula::Base {
+                    name: name.clone(),
+                    formula,
+                }
+            }),
+            PackageFormula::Conflict { name, formula } => filtered(formula).map(|formula| {
+                PackageFormula::Conflict {
+                    name: name.clone(),
+                    formula,
+                }
+            }),
+            PackageFormula::Depopt { name, formula } => filtered(formula).map(|formula| {
+                PackageFormula::Depopt {
+                    name: name.clone(),
+                    formula,
+                }
+            }),
+            PackageFormula::ConflictClass { .. } => Some(self.clone()),
+        }
+    }
+
+    /// Adds the values each variable is compared against in the formula.
+    pub fn collect_variable_values(&self, found: &mut BTreeMap<String, BTreeSet<OpamVersion>>) {
+        match self {
+            PackageFormula::And(binary) | PackageFormula::Or(binary) => {
+                binary.lhs.collect_variable_values(found);
+                binary.rhs.collect_variable_values(found);
+            }
+            PackageFormula::Base { name: _, formula }
+            | PackageFormula::Conflict { name: _, formula }
+            | PackageFormula::Depopt { name: _, formula } => {
+                formula.collect_variable_values(found)
+            }
+            PackageFormula::ConflictClass { .. } => (),
+        }
+    }
+}
+
 impl Index {
     pub fn new(repo: String) -> Self {
+        Self::with_repository(Arc::new(Repository::new(repo)))
+    }
+
+    pub fn with_repository(repository: Arc<Repository>) -> Self {
         Self {
-            repo,
-            debug: false.into(),
-            version_debug: false.into(),
+            repository,
+            debug: false,
+            version_debug: false,
+            error_policy: ErrorPolicy::Fail,
+            variable_policy: VariablePolicy::Free,
+            prioritizer: Prioritizer::default(),
+            version_strategy: VersionStrategy::Newest,
+            environment: BTreeMap::new(),
+            domains: BTreeMap::new(),
+            package_variables: BTreeMap::new(),
+            undefined: BTreeSet::new(),
+        }
+    }
+
+    /// A solver for a single solve with the index as it is now.
+    pub fn solver(&self) -> Solver {
+        Solver {
+            index: self.clone(),
+            missing: RefCell::new(BTreeSet::new()),
+            variables: RefCell::new(BTreeMap::new()),
+            conflicts: RefCell::new(Set::default()),
+            parents: RefCell::new(Map::default()),
+        }
+    }
+
+    /// Solves for a version of a package, with a solver of its own.
+    #[allow(clippy::result_large_err)]
+    pub fn solve(&self, package: Package, version: OpamVersion) -> Result<Solution, PubGrubError<Solver>> {
+        self.solver().solve(package, version)
+    }
+
+    pub fn repo(&self) -> &str {
+        self.repository.path.as_str()
+    }
+
+    /// Packages in the repository with names close to `package`, closest first.
+    pub fn suggest_packages(&self, package: &str) -> Result<Vec<PackageName>, Error> {
+        let max_distance = (package.chars().count() / 3).max(1);
+        let mut suggestions: Vec<(usize, PackageName)> = self
+            .repository
+            .packages()?
+            .iter()
+            .map(|name| (edit_distance(package, name), name.clone()))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .collect();
+        suggestions.sort();
+        Ok(suggestions.into_iter().take(3).map(|(_, name)| name).collect())
+    }
+
+    pub fn set_debug(&mut self, flag: bool) {
+        self.debug = flag;
+    }
+
+    pub fn set_version_debug(&mut self, flag: bool) {
+        self.version_debug = flag;
+    }
+
+    /// The value of a variable in the environment, if it is set.
+    pub fn variable(&self, var: &str) -> Option<OpamVersion> {
+        self.environment.get(var).cloned()
+    }
+
+    pub fn set_variable(&mut self, var: &str, value: &str) {
+        self.environment
+            .insert(var.to_string(), OpamVersion(value.to_string()));
+    }
+
+    /// Leaves a variable for the solver to choose.
+    pub fn unset_variable(&mut self, var: &str) {
+        self.environment.remove(var);
+    }
+
+    /// Sets the value of a package variable such as `ocaml:native` for a version of the
+    /// package.
+    pub fn set_package_variable(&mut self, package: &str, version: &str, var: &str, value: &str) {
+        self.package_variables
+            .entry((package.to_string(), var.to_string()))
+            .or_default()
+            .insert(OpamVersion(version.to_string()), OpamVersion(value.to_string()));
+    }
+
+    /// The versions of `package` for which its variable `var` is related to `value` by
+    /// `relop`. `version` and `installed` come from the package itself and other
+    /// variables from the values set for each version. As in opam, a variable is
+    /// undefined for the versions it has no value for, and all the variables of a
+    /// package that isn't installed are except for `installed`, so for those versions
+    /// it's never related to anything, and neither a filter nor its negation holds.
+    pub fn package_variable_range(
+        &self,
+        package: &str,
+        var: &str,
+        relop: &RelOp,
+        value: &OpamVersion,
+    ) -> Range<OpamVersion> {
+        let holds = relop_to_range(relop, value.clone());
+        let not_installed = Range::singleton(NOT_INSTALLED_VERSION.clone());
+        match var {
+            "version" => holds.intersection(&installed_range()),
+            "installed" => {
+                let mut range = Range::empty();
+                if holds.contains(&TRUE_VERSION) {
+                    range = range.union(&installed_range());
+                }
+                if holds.contains(&FALSE_VERSION) {
+                    range = range.union(&not_installed);
+                }
+                range
+            }
+            _ => self
+                .package_variables
+                .get(&(package.to_string(), var.to_string()))
+                .into_iter()
+                .flatten()
+                .filter(|(_, value)| holds.contains(value))
+                .fold(Range::empty(), |range, (version, _)| {
+                    range.union(&Range::singleton(version.clone()))
+                }),
+        }
+    }
+
+    /// The versions of `package` for which its variable `var` is defined: those it's
+    /// installed at for `version`, every version for `installed`, and those with a value
+    /// for other variables.
+    pub fn package_variable_defined(&self, package: &str, var: &str) -> Range<OpamVersion> {
+        match var {
+            "version" => installed_range(),
+            "installed" => Range::full(),
+            _ => self
+                .package_variables
+                .get(&(package.to_string(), var.to_string()))
+                .into_iter()
+                .flatten()
+                .fold(Range::empty(), |range, (version, _)| {
+                    range.union(&Range::singleton(version.clone()))
+                }),
+        }
+    }
+
+    /// Sets the variables in a profile, a file of opam fields such as `os: "linux"`.
+    pub fn load_profile(&mut self, path: &Path) -> Result<(), Error> {
+        let variables = read_profile(path)?;
+        self.environment.extend(variables);
+        Ok(())
+    }
+
+    /// Sets `os`, `arch`, `os-family`, `os-distribution` and `os-version` to those of
+    /// the machine we're running on. This overwrites the values of those variables set
+    /// earlier, including by `load_profile`, while variables set afterwards take
+    /// precedence.
+    pub fn detect_host(&mut self) {
+        self.environment.extend(host_variables());
+    }
+
+    /// Declares the values a variable may take, most preferred first. Without a declared
+    /// domain a variable takes the values it is compared against, in version order, or
+    /// `false` and `true` if it is never compared against a value.
+    pub fn declare_domain(&mut self, var: &str, values: &[&str]) {
+        self.domains.insert(
+            var.to_string(),
+            values.iter().map(|value| OpamVersion(value.to_string())).collect(),
+        );
+    }
+
+    /// Declares that a variable may be undefined, as variables tested with `?` are.
+    pub fn declare_undefined(&mut self, var: &str) {
+        self.undefined.insert(var.to_string());
+    }
+
+    /// Whether the solver may leave a variable undefined. Package variables, those in
+    /// the environment and those not declared undefined are never undefined.
+    pub fn may_be_undefined(&self, var: &str) -> bool {
+        split_package_variable(var).is_none()
+            && self.variable(var).is_none()
+            && self.undefined.contains(var)
+    }
+
+    /// Declares the domain of every variable compared against a value anywhere in the
+    /// repository, and every variable tested with `?` undefined, so that the domains
+    /// don't depend on which packages a solve visits. Domains that are already
+    /// declared are kept.
+    pub fn scan_domains(&mut self) -> Result<(), Error> {
+        for (var, values) in self.repository.variable_values()? {
+            if values.contains(&UNDEFINED_VERSION) {
+                self.undefined.insert(var.clone());
+            }
+            let values: Vec<_> = values.iter().filter(|value| **value != *UNDEFINED_VERSION).cloned().collect();
+            if !values.is_empty() {
+                self.domains.entry(var.clone()).or_insert(values);
+            }
+        }
+        Ok(())
+    }
+
+    /// How `with-test`, `build` and the other per-package variables are decided.
+    pub fn set_variable_policy(&mut self, policy: VariablePolicy) {
+        self.variable_policy = policy;
+    }
+
+    /// The value of a per-package variable for a package version, if the variable
+    /// policy decides it.
+    pub fn package_variable(&self, package: &str, version: &OpamVersion, var: &str) -> Option<bool> {
+        match &self.variable_policy {
+            VariablePolicy::Free => None,
+            VariablePolicy::Opam {
+                with_test,
+                with_doc,
+            } => match var {
+                // `test` and `doc` are the names used before opam 2.0
+                "with-test" | "test" => Some(with_test.contains(package)),
+                "with-doc" | "doc" => Some(with_doc.contains(package)),
+                "build" | "post" => Some(true),
+                "dev" => Some(is_dev_version(version)),
+                _ => None,
+            },
+        }
+    }
+
+    /// The value of a variable in the filters of a package version, if it's known before
+    /// solving: the package's own `name` and `version`, the variables the variable
+    /// policy decides, and the package variables set for the version itself.
+    pub fn resolve_variable(&self, package: &str, version: &OpamVersion, var: &str) -> Option<OpamVersion> {
+        let (var, scoped) = match split_package_variable(var) {
+            Some(("_", var)) => (var, true),
+            Some((scope, var)) if scope == package => (var, true),
+            Some(_) => return None,
+            None => (var, false),
+        };
+        match var {
+            "name" => Some(OpamVersion(package.to_string())),
+            "version" => Some(version.clone()),
+            _ => self
+                .package_variable(package, version, var)
+                .map(|b| {
+                    if b {
+                        TRUE_VERSION.clone()
+                    } else {
+                        FALSE_VERSION.clone()
+                    }
+                })
+                .or_else(|| {
+                    if !scoped {
+                        return None;
+                    }
+                    let values = self.package_variables.get(&(package.to_string(), var.to_string()))?;
+                    values.get(version).cloned()
+                }),
+        }
+    }
+
+    /// Whether a package version that can't be read or encoded aborts the solve
+    /// or is treated as unavailable.
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_policy = policy;
+    }
+
+    /// Which versions of each package are tried first.
+    pub fn set_version_strategy(&mut self, strategy: VersionStrategy) {
+        self.version_strategy = strategy;
+    }
+
+    /// Whether a package version has the given flag, e.g. `avoid-version`.
+    pub fn has_flag(&self, package: &str, version: &OpamVersion, flag: &str) -> Result<bool, Error> {
+        let file = self.repository.opam_file(package, version)?;
+        Ok(file.flags.iter().any(|f| f == flag))
+    }
+
+    /// Whether a package version is flagged `deprecated`.
+    pub fn is_deprecated(&self, package: &str, version: &OpamVersion) -> Result<bool, Error> {
+        self.has_flag(package, version, "deprecated")
+    }
+
+    /// Orders the available versions of a package, newest first, by the version strategy.
+    /// Versions flagged `avoid-version` or `deprecated` come last, so they're only
+    /// chosen when no other version will do, unless they're locked. A package to upgrade
+    /// loses the versions older than its locked version.
+    pub fn prefer_versions(
+        &self,
+        package: &str,
+        mut versions: Vec<OpamVersion>,
+    ) -> Result<Vec<OpamVersion>, Error> {
+        let flags = self.repository.flags(package)?;
+        if self.version_strategy == VersionStrategy::Oldest {
+            versions.reverse();
+        }
+        let (mut versions, avoided): (Vec<_>, Vec<_>) = versions.into_iter().partition(|version| {
+            !flags.get(version).is_some_and(|flags| {
+                flags.iter().any(|flag| flag == "avoid-version" || flag == "deprecated")
+            })
+        });
+        versions.extend(avoided);
+        match &self.version_strategy {
+            VersionStrategy::Newest | VersionStrategy::Oldest => (),
+            VersionStrategy::Locked { versions: locked, upgrade } => match locked.get(package) {
+                Some(locked) if upgrade.contains(package) => versions.retain(|v| v >= locked),
+                Some(locked) => {
+                    if let Some(position) = versions.iter().position(|v| v == locked) {
+                        let locked = versions.remove(position);
+                        versions.insert(0, locked);
+                    }
+                }
+                None => (),
+            },
+        }
+        Ok(versions)
+    }
+
+    /// The order in which PubGrub decides packages.
+    pub fn set_prioritizer(&mut self, prioritizer: Prioritizer) {
+        self.prioritizer = prioritizer;
+    }
+}
+
+impl Solver {
+    /// The index being solved with.
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /// Whether to print each package version's dependencies as they're encoded,
+    /// overriding the index's setting.
+    pub fn set_debug(&mut self, flag: bool) {
+        self.index.set_debug(flag);
+    }
+
+    /// Solves for a version of a package, returning the packages that are installed. A
+    /// solver is meant for a single solve, as the state it discovers carries over to the
+    /// next.
+    // the error is PubGrub's own
+    #[allow(clippy::result_large_err)]
+    pub fn solve(&self, package: Package, version: OpamVersion) -> Result<Solution, PubGrubError<Solver>> {
+        pubgrub::resolve(self, package, version).map(|solution| installed(&solution))
+    }
+
+    pub fn available_versions(&self, package: &PackageName) -> Result<Vec<OpamVersion>, Error> {
+        match self.index.repository.versions(package).map(|versions| versions.to_vec()) {
+            // a package that doesn't exist has no versions, which PubGrub can report on
+            Err(Error {
+                kind: ErrorKind::MissingPackage,
+                ..
+            }) => {
+                self.missing.borrow_mut().insert(package.clone());
+                Ok(vec![])
+            }
+            result => result,
         }
     }
 
-    pub fn available_versions(&self, package: &PackageName) -> Vec<OpamVersion> {
-        available_versions_from_repo(self.repo.as_str(), package).unwrap()
+    /// Whether a dependency of a package version only comes from its conflicts, in
+    /// which case the version doesn't depend on it being installed.
+    pub fn is_conflict(&self, package: &Package, version: &OpamVersion, dependency: &Package) -> bool {
+        self.conflicts
+            .borrow()
+            .contains(&(package.clone(), version.clone(), dependency.clone()))
     }
 
-    pub fn set_debug(&self, flag: bool) {
-        self.debug.set(flag);
+    /// The packages that were depended on but aren't in the repository.
+    pub fn missing_packages(&self) -> Vec<PackageName> {
+        self.missing.borrow().iter().cloned().collect()
     }
 
-    pub fn set_version_debug(&self, flag: bool) {
-        self.version_debug.set(flag);
+    /// The packages not in the repository that a failed solve needed, i.e. those with
+    /// no versions in its derivation tree. Others, such as those only named in the
+    /// `conflicts` or `depopts` of a version, had nothing to do with the failure.
+    pub fn missing_packages_in(
+        &self,
+        derivation_tree: &DerivationTree<Package, Range<OpamVersion>, String>,
+    ) -> Vec<PackageName> {
+        let missing = self.missing.borrow();
+        let mut needed = BTreeSet::new();
+        let mut pending = vec![derivation_tree];
+        while let Some(tree) = pending.pop() {
+            match tree {
+                DerivationTree::External(External::NoVersions(Package::Base(name), _))
+                    if missing.contains(name) =>
+                {
+                    needed.insert(name.clone());
+                }
+                DerivationTree::External(_) => (),
+                DerivationTree::Derived(derived) => {
+                    pending.push(&derived.cause1);
+                    pending.push(&derived.cause2);
+                }
+            }
+        }
+        needed.into_iter().collect()
+    }
+
+    /// The package version whose opam file a package comes from: the version itself
+    /// for a package in the repository, and for a synthetic package the version whose
+    /// formulas it encodes. A synthetic package encoding the same formula for more than
+    /// one opam file, or for the request, has no single origin, so this is `None`.
+    pub fn origin(&self, package: &Package, version: &OpamVersion) -> Option<(PackageName, OpamVersion)> {
+        let parents = self.parents.borrow();
+        let mut origin = None;
+        let mut seen = Set::default();
+        let mut pending = vec![(package.clone(), version.clone())];
+        while let Some((package, version)) = pending.pop() {
+            match package {
+                Package::Base(pkg) => match &origin {
+                    None => origin = Some((pkg, version)),
+                    Some(found) if *found == (pkg, version) => (),
+                    Some(_) => return None,
+                },
+                Package::Root(_) => return None,
+                _ => {
+                    if seen.insert(package.clone()) {
+                        pending.extend(parents.get(&package)?.iter().cloned());
+                    }
+                }
+            }
+        }
+        origin
+    }
+
+    /// The values a variable chosen by the solver may take, in order of preference.
+    pub fn domain(&self, var: &str) -> Vec<OpamVersion> {
+        if let Some(domain) = self.index.domains.get(var) {
+            return domain.clone();
+        }
+        match self.variables.borrow().get(var) {
+            Some(values) => values.iter().cloned().collect(),
+            None => vec![FALSE_VERSION.clone(), TRUE_VERSION.clone()],
+        }
+    }
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
     }
+    previous[b.len()]
 }
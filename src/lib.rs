@@ -1,4 +1,8 @@
 pub mod opam_version;
+pub mod error;
 pub mod index;
 pub mod opam_deps;
+pub mod opam_parser;
 pub mod parse;
+pub mod platform;
+pub mod report;
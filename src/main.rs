@@ -1,78 +1,81 @@
-use pubgrub::{DefaultStringReporter, Dependencies, DependencyProvider, PubGrubError, Reporter, SelectedDependencies};
-use pubgrub_opam::index::Index;
+use pubgrub::{DerivationTree, PubGrubError, Range, Reporter};
+use pubgrub_opam::index::{Index, Solution, Solver};
 use pubgrub_opam::opam_deps::Package;
 use pubgrub_opam::opam_version::OpamVersion;
-use std::collections::{BTreeMap, HashSet};
+use pubgrub_opam::report::{resolved_graph, JsonDocument, OpamReportFormatter, OpamReporter};
 use std::error::Error;
 use std::str::FromStr;
 
-fn solve_repo(pkg: Package, version: OpamVersion, repo: &str) -> Result<SelectedDependencies<Index>, Box<dyn Error>> {
-    let index = Index::new(repo.to_string());
-    index.set_debug(true);
-
-    let sol: SelectedDependencies<Index> =
-        match pubgrub::resolve(&index, pkg, version) {
-            Ok(sol) => Ok(sol),
-            Err(PubGrubError::NoSolution(mut derivation_tree)) => {
-                derivation_tree.collapse_no_versions();
-                eprintln!("\n\n\n{}", DefaultStringReporter::report(&derivation_tree));
-                Err(PubGrubError::<Index>::NoSolution(derivation_tree))
-            }
-            Err(err) => panic!("{:?}", err),
-        }?;
-
-    index.set_debug(false);
-
-    fn get_resolved_deps<'a>(
-        index: &'a Index,
-        sol: &'a SelectedDependencies<Index>,
-        package: &Package,
-        version: &'a OpamVersion,
-    ) -> HashSet<(String, &'a OpamVersion)> {
-        let dependencies = index.get_dependencies(&package, &version);
-        match dependencies {
-            Ok(Dependencies::Available(constraints)) => {
-                let mut dependents = HashSet::new();
-                for (dep_package, _dep_versions) in constraints {
-                    let solved_version = sol.get(&dep_package).unwrap();
-                    match dep_package.clone() {
-                        Package::Base(name) => {
-                            dependents.insert((name, solved_version));
-                        }
-                        Package::Lor { lhs : _, rhs : _ } => {
-                            dependents.extend(get_resolved_deps(&index, sol, &dep_package, solved_version));
-                        }
-                        Package::Proxy { name : _, formula : _ } => {
-                            dependents.extend(get_resolved_deps(&index, sol, &dep_package, solved_version));
-                        }
-                        Package::Formula { name : _, formula : _ } => {
-                            dependents.extend(get_resolved_deps(&index, sol, &dep_package, solved_version));
-                        }
-                        Package::Var(_) => {
-                            dependents.insert((format!("{}", dep_package), solved_version));
-                        }
-                        Package::Root(_deps) => {
-                            dependents.extend(get_resolved_deps(&index, sol, &dep_package, solved_version));
-                        }
-                        Package::ConflictClass(_) => {
-                            dependents.extend(get_resolved_deps(&index, sol, &dep_package, solved_version));
-                        }
-                    };
-                }
-                dependents
-            }
-            _ => {
-                println!("No available dependencies for package {}", package);
-                HashSet::new()
-            }
+/// How the outcome of a solve is reported: as text, with the explanation of a failed
+/// solve on stderr, or as a single JSON document on stdout for tools to consume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Explanation {
+    Text,
+    Json,
+}
+
+/// Explains a failed solve as text, with the missing packages it needed and the
+/// packages they may have been meant to be.
+fn explain(
+    solver: &Solver,
+    derivation_tree: &DerivationTree<Package, Range<OpamVersion>, String>,
+) -> Result<String, Box<dyn Error>> {
+    let formatter = OpamReportFormatter::new(solver);
+    let mut text = format!("\n\n\n{}\n", OpamReporter::report_with_formatter(derivation_tree, &formatter));
+    for package in solver.missing_packages_in(derivation_tree) {
+        text.push_str(&format!("package {} is not in the repository", package));
+        let suggestions = solver.index().suggest_packages(&package)?;
+        if !suggestions.is_empty() {
+            text.push_str(&format!(", did you mean {}?", suggestions.join(", ")));
+        }
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+fn solve_index_explained(
+    index: &Index,
+    pkg: Package,
+    version: OpamVersion,
+    explanation: Explanation,
+) -> Result<Solution, Box<dyn Error>> {
+    let mut solver = index.solver();
+    // the debug output would get in the way of the JSON document
+    solver.set_debug(explanation == Explanation::Text);
+    let result = solver.solve(pkg, version).map_err(|err| match err {
+        PubGrubError::NoSolution(mut derivation_tree) => {
+            derivation_tree.collapse_no_versions();
+            PubGrubError::NoSolution(derivation_tree)
         }
+        err => err,
+    });
+    solver.set_debug(false);
+
+    if explanation == Explanation::Json {
+        // stdout holds nothing but the document, whatever the outcome
+        let document = JsonDocument::new(&solver, &result);
+        println!("{}", serde_json::to_string_pretty(&document)?);
+        return Ok(result?);
     }
 
+    let sol = match result {
+        Ok(sol) => sol,
+        Err(PubGrubError::NoSolution(derivation_tree)) => {
+            eprint!("{}", explain(&solver, &derivation_tree)?);
+            return Err(Box::new(PubGrubError::<Solver>::NoSolution(derivation_tree)));
+        }
+        Err(err) => return Err(Box::new(err)),
+    };
+
     println!("\nSolution Set:");
     for (package, version) in &sol {
         match package {
             Package::Base(name) => {
-                println!("\t({}, {})", name, version);
+                if index.is_deprecated(name, version)? {
+                    println!("\t({}, {}) (deprecated)", name, version);
+                } else {
+                    println!("\t({}, {})", name, version);
+                }
             }
             Package::Var(name) => {
                 println!("\t{} = {}", name, version);
@@ -81,23 +84,10 @@ fn solve_repo(pkg: Package, version: OpamVersion, repo: &str) -> Result<Selected
         }
     }
 
-    let mut resolved_graph: BTreeMap<(String, &OpamVersion), Vec<(String, &OpamVersion)>> =
-        BTreeMap::new();
-    for (package, version) in &sol {
-        match package {
-            Package::Base(name) => {
-                let mut deps = get_resolved_deps(&index, &sol, &package, version).into_iter().collect::<Vec<_>>();
-                deps.sort_by(|(p1, _v1), (p2, _v2)| p1.cmp(p2));
-                resolved_graph.insert((name.clone(), version), deps);
-            }
-            _ => {}
-        }
-    }
-
     println!("\nResolved Dependency Graph:");
-    for ((name, version), dependents) in resolved_graph {
+    for ((name, version), dependents) in resolved_graph(&solver, &sol) {
         print!("\t({}, {})", name, version);
-        if dependents.len() > 0 {
+        if !dependents.is_empty() {
             print!(" -> ")
         }
         let mut first = true;
@@ -114,12 +104,30 @@ fn solve_repo(pkg: Package, version: OpamVersion, repo: &str) -> Result<Selected
     Ok(sol)
 }
 
+/// Solves for `[--json] [PACKAGE [VERSION]]` in the example repository: a version of
+/// the package if one is given, the newest that can be installed if not, and A 1.0.0
+/// without arguments.
 fn main() -> Result<(), Box<dyn Error>> {
-    let _ = solve_repo(
-        Package::from_str("A").unwrap(),
-        "1.0.0".parse::<OpamVersion>().unwrap(),
-        "./example-repo/packages",
-    );
+    let repo = "./example-repo/packages";
+    let mut explanation = Explanation::Text;
+    let mut operands = vec![];
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => explanation = Explanation::Json,
+            option if option.starts_with('-') => return Err(format!("unknown option {}", option).into()),
+            _ => operands.push(arg),
+        }
+    }
+    let (pkg, version) = match operands.as_slice() {
+        [] => (Package::from_str("A")?, "1.0.0".parse::<OpamVersion>()?),
+        [name] => (
+            Package::Root(vec![(Package::from_str(name)?, Range::full())]),
+            OpamVersion("".to_string()),
+        ),
+        [name, version] => (Package::from_str(name)?, version.parse::<OpamVersion>()?),
+        _ => return Err("usage: pubgrub_opam [--json] [PACKAGE [VERSION]]".into()),
+    };
+    solve_index_explained(&Index::new(repo.to_string()), pkg, version, explanation)?;
     Ok(())
 }
 
@@ -127,10 +135,32 @@ fn main() -> Result<(), Box<dyn Error>> {
 mod tests {
 
     use pubgrub::Range;
-    use pubgrub_opam::opam_deps::{FALSE_VERSION, TRUE_VERSION};
+    use std::path::Path;
+    use std::collections::BTreeSet;
+    use pubgrub_opam::error::{ErrorKind, ErrorPolicy};
+    use pubgrub_opam::opam_deps::{FALSE_VERSION, NOT_INSTALLED_VERSION, TRUE_VERSION, UNDEFINED_VERSION};
+    use pubgrub_opam::parse::RelOp;
+    use pubgrub_opam::platform::{normalise_arch, normalise_os};
+    use pubgrub_opam::index::{
+        installed, is_dev_version, Prioritizer, Priority, Repository, VariablePolicy, VersionStrategy,
+    };
+    use pubgrub::{Dependencies, DependencyProvider};
+    use pubgrub_opam::report::{get_resolved_deps, JSON_VERSION};
+    use std::cmp::Reverse;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::Instant;
 
     use super::*;
 
+fn solve_repo(pkg: Package, version: OpamVersion, repo: &str) -> Result<Solution, Box<dyn Error>> {
+    solve_index(&Index::new(repo.to_string()), pkg, version)
+}
+
+fn solve_index(index: &Index, pkg: Package, version: OpamVersion) -> Result<Solution, Box<dyn Error>> {
+    solve_index_explained(index, pkg, version, Explanation::Text)
+}
+
     #[test]
     fn test_simple_solve() -> Result<(), Box<dyn Error>> {
         solve_repo(
@@ -380,6 +410,1376 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_conflicts_version() -> Result<(), Box<dyn Error>> {
+        let sol = solve_repo(
+            Package::from_str("conflicts-version").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::from_str("C").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        assert_eq!(sol.get(&Package::from_str("D").unwrap()), Some("2.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        let solver = Index::new("./example-repo/packages".to_string()).solver();
+        let version = "1.0.0".parse::<OpamVersion>().unwrap();
+        let deps = get_resolved_deps(&solver, &sol, &Package::from_str("conflicts-version").unwrap(), &version);
+        assert!(deps.iter().any(|(name, _)| name == "C"));
+        assert!(deps.iter().all(|(name, _)| name != "D"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicts_error() -> Result<(), Box<dyn Error>> {
+        let result = solve_repo(
+            Package::from_str("conflicts-error").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        );
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicts_absent() -> Result<(), Box<dyn Error>> {
+        let sol = solve_repo(
+            Package::from_str("conflicts-absent").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        )?;
+        // a solution only has the packages that are installed
+        assert_eq!(sol.get(&Package::from_str("F").unwrap()), None);
+        assert!(sol.contains_key(&Package::from_str("conflicts-absent").unwrap()));
+        assert!(sol.contains_key(&Package::from_str("B").unwrap()));
+
+        // unlike what PubGrub selects, which `installed` leaves them out of
+        let solver = Index::new("./example-repo/packages".to_string()).solver();
+        let sol = pubgrub::resolve(&solver, Package::from_str("conflicts-absent").unwrap(), "1.0.0".parse::<OpamVersion>().unwrap())?;
+        assert_eq!(sol.get(&Package::from_str("F").unwrap()), Some(&*NOT_INSTALLED_VERSION));
+        assert_eq!(installed(&sol).get(&Package::from_str("F").unwrap()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicts_filtered() -> Result<(), Box<dyn Error>> {
+        let sol = solve_repo(
+            Package::from_str("conflicts-filtered").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::Var("test".to_string())), Some("false".parse::<OpamVersion>().as_ref().unwrap()));
+        assert_eq!(sol.get(&Package::from_str("C").unwrap()), Some("2.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicts_filtered_set_test_true() -> Result<(), Box<dyn Error>> {
+        let root = Package::Root(vec![
+            (Package::Base("conflicts-filtered".to_string()), Range::singleton(OpamVersion("1.0.0".to_string()))),
+            (Package::Var("test".to_string()), Range::singleton(TRUE_VERSION.clone())),
+        ]);
+        let sol = solve_repo(
+            root,
+            OpamVersion("".to_string()),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::from_str("C").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        assert_eq!(sol.get(&Package::from_str("D").unwrap()), Some("2.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_depopts_version() -> Result<(), Box<dyn Error>> {
+        let sol = solve_repo(
+            Package::from_str("depopts-version").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::from_str("C").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        assert_eq!(sol.get(&Package::from_str("D").unwrap()), Some("2.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        let solver = Index::new("./example-repo/packages".to_string()).solver();
+        let version = "1.0.0".parse::<OpamVersion>().unwrap();
+        let deps = get_resolved_deps(&solver, &sol, &Package::from_str("depopts-version").unwrap(), &version);
+        assert!(deps.contains(&("D".to_string(), &"2.0.0".parse::<OpamVersion>().unwrap())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_depopts_absent() -> Result<(), Box<dyn Error>> {
+        let sol = solve_repo(
+            Package::from_str("depopts-absent").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::from_str("F").unwrap()), None);
+        let solver = Index::new("./example-repo/packages".to_string()).solver();
+        let version = "1.0.0".parse::<OpamVersion>().unwrap();
+        let deps = get_resolved_deps(&solver, &sol, &Package::from_str("depopts-absent").unwrap(), &version);
+        assert!(deps.iter().all(|(name, _)| name != "F"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_depopts_filtered() -> Result<(), Box<dyn Error>> {
+        let sol = solve_repo(
+            Package::from_str("depopts-filtered").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::Var("test".to_string())), Some("false".parse::<OpamVersion>().as_ref().unwrap()));
+        assert_eq!(sol.get(&Package::from_str("C").unwrap()), Some("2.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        assert_eq!(sol.get(&Package::from_str("D").unwrap()), Some("3.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_depopts_filtered_set_test_true() -> Result<(), Box<dyn Error>> {
+        let root = Package::Root(vec![
+            (Package::Base("depopts-filtered".to_string()), Range::singleton(OpamVersion("1.0.0".to_string()))),
+            (Package::Var("test".to_string()), Range::singleton(TRUE_VERSION.clone())),
+        ]);
+        let sol = solve_repo(
+            root,
+            OpamVersion("".to_string()),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::from_str("C").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        assert_eq!(sol.get(&Package::from_str("D").unwrap()), Some("2.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_available() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.set_variable("os", "macos");
+        index.set_variable("arch", "arm64");
+        let root = Package::Root(vec![
+            (Package::Base("available".to_string()), Range::full()),
+        ]);
+        let sol = solve_index(
+            &index,
+            root,
+            OpamVersion("".to_string()),
+        )?;
+        assert_eq!(sol.get(&Package::from_str("available").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_available_error() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.set_variable("os", "macos");
+        index.set_variable("arch", "arm64");
+        let result = solve_index(
+            &index,
+            Package::from_str("available").unwrap(),
+            "2.0.0".parse::<OpamVersion>().unwrap(),
+        );
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_available_profile() -> Result<(), Box<dyn Error>> {
+        let root = Package::Root(vec![
+            (Package::Base("available".to_string()), Range::full()),
+        ]);
+
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.load_profile(Path::new("./example-repo/profiles/macos-arm64"))?;
+        let sol = solve_index(&index, root.clone(), OpamVersion("".to_string()))?;
+        assert_eq!(sol.get(&Package::from_str("available").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.load_profile(Path::new("./example-repo/profiles/linux-x86_64"))?;
+        assert_eq!(index.variable("os-family"), Some(OpamVersion("debian".to_string())));
+        let sol = solve_index(&index, root, OpamVersion("".to_string()))?;
+        assert_eq!(sol.get(&Package::from_str("available").unwrap()), Some("2.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        assert_eq!(sol.get(&Package::Var("os".to_string())), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_available_unset_variables() -> Result<(), Box<dyn Error>> {
+        // with no environment the solver is free to pick a platform for the newest version
+        let root = Package::Root(vec![
+            (Package::Base("available".to_string()), Range::full()),
+        ]);
+        let sol = solve_repo(
+            root,
+            OpamVersion("".to_string()),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::from_str("available").unwrap()), Some("2.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_available_variable() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.load_profile(Path::new("./example-repo/profiles/macos-arm64"))?;
+        let sol = solve_index(
+            &index,
+            Package::from_str("available-variable").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        assert_eq!(sol.get(&Package::Var("with-test".to_string())), Some(&*TRUE_VERSION));
+        assert_eq!(sol.get(&Package::from_str("available").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_and() -> Result<(), Box<dyn Error>> {
+        let sol = solve_repo(
+            Package::from_str("group-and").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::from_str("B").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        assert_eq!(sol.get(&Package::from_str("C").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        assert_eq!(sol.get(&Package::from_str("D").unwrap()), Some("2.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_and_error() -> Result<(), Box<dyn Error>> {
+        let result = solve_repo(
+            Package::from_str("group-and-error").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        );
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_empty() -> Result<(), Box<dyn Error>> {
+        let sol = solve_repo(
+            Package::from_str("group-empty").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::from_str("D").unwrap()), Some("2.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_empty_negated() -> Result<(), Box<dyn Error>> {
+        // an empty group is `true`, so `!()` drops the dependency it filters
+        for (build, sibling) in [("true", None), ("false", Some("2.0.0"))] {
+            let mut index = Index::new("./example-repo/packages".to_string());
+            index.set_variable("build", build);
+            let sol = solve_index(
+                &index,
+                Package::from_str("group-empty-negated").unwrap(),
+                "1.0.0".parse::<OpamVersion>().unwrap(),
+            )?;
+            assert_eq!(sol.get(&Package::from_str("D").unwrap()), None);
+            assert_eq!(sol.get(&Package::from_str("sibling").unwrap()), sibling.map(|v| v.parse::<OpamVersion>().unwrap()).as_ref());
+        }
+
+        // and the solver can decide the variables around it
+        let sol = solve_repo(
+            Package::from_str("group-empty-negated").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::from_str("D").unwrap()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditions_multiple() -> Result<(), Box<dyn Error>> {
+        let sol = solve_repo(
+            Package::from_str("conditions-multiple").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::from_str("D").unwrap()), Some("2.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_group() -> Result<(), Box<dyn Error>> {
+        let root = Package::Root(vec![
+            (Package::Base("filter-group".to_string()), Range::singleton(OpamVersion("1.0.0".to_string()))),
+            (Package::Var("build".to_string()), Range::singleton(TRUE_VERSION.clone())),
+            (Package::Var("test".to_string()), Range::singleton(TRUE_VERSION.clone())),
+        ]);
+        let sol = solve_repo(
+            root,
+            OpamVersion("".to_string()),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::from_str("C").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_group_set_test_false() -> Result<(), Box<dyn Error>> {
+        let root = Package::Root(vec![
+            (Package::Base("filter-group".to_string()), Range::singleton(OpamVersion("1.0.0".to_string()))),
+            (Package::Var("build".to_string()), Range::singleton(TRUE_VERSION.clone())),
+            (Package::Var("test".to_string()), Range::singleton(FALSE_VERSION.clone())),
+        ]);
+        let sol = solve_repo(
+            root,
+            OpamVersion("".to_string()),
+            "./example-repo/packages",
+        )?;
+        assert_ne!(sol.get(&Package::from_str("C").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_policy_fail() -> Result<(), Box<dyn Error>> {
+        let index = Index::new("./example-repo/packages".to_string());
+        let root = Package::Root(vec![
+            (Package::Base("malformed".to_string()), Range::full()),
+        ]);
+        let result = index.solve(root, OpamVersion("".to_string()));
+        match result {
+            Err(PubGrubError::ErrorRetrievingDependencies { source, .. }) => {
+                assert_eq!(source.package, Some("malformed".to_string()));
+                assert_eq!(source.version, Some("2.0.0".parse::<OpamVersion>().unwrap()));
+                assert!(matches!(source.kind, ErrorKind::Syntax(_)));
+            }
+            _ => panic!("expected an error retrieving the dependencies of malformed.2.0.0"),
+        }
+
+        // malformed.2.0.0 is only read when it's picked
+        let sol = solve_index(
+            &index,
+            Package::from_str("malformed-dependent").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        assert_eq!(sol.get(&Package::from_str("malformed").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_package_variables_compared() -> Result<(), Box<dyn Error>> {
+        // neither comparison has a free variable whose values a proxy could pick from
+        let index = Index::new("./example-repo/packages".to_string());
+        for version in ["1.0.0", "2.0.0"] {
+            let root = Package::Root(vec![(
+                Package::Base("package-variables-compared".to_string()),
+                Range::singleton(version.parse::<OpamVersion>()?),
+            )]);
+            match index.solve(root, OpamVersion("".to_string())) {
+                Err(PubGrubError::ErrorRetrievingDependencies { source, .. }) => {
+                    assert!(matches!(source.kind, ErrorKind::UnsupportedComparison(_)));
+                }
+                _ => panic!("expected package-variables-compared.{} to be rejected", version),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_policy_unavailable() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.set_error_policy(ErrorPolicy::Unavailable);
+        let root = Package::Root(vec![
+            (Package::Base("malformed".to_string()), Range::full()),
+        ]);
+        let sol = index.solve(root, OpamVersion("".to_string()))?;
+        assert_eq!(sol.get(&Package::from_str("malformed").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        assert_eq!(sol.get(&Package::from_str("D").unwrap()), Some("3.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_package() -> Result<(), Box<dyn Error>> {
+        let index = Index::new("./example-repo/packages".to_string());
+        let solver = index.solver();
+        let result = solver.solve(Package::from_str("missing-package").unwrap(), "1.0.0".parse::<OpamVersion>().unwrap());
+        assert!(matches!(result, Err(PubGrubError::NoSolution(_))));
+        assert_eq!(solver.missing_packages(), vec!["package-formul".to_string()]);
+        assert_eq!(index.suggest_packages("package-formul")?.first(), Some(&"package-formula".to_string()));
+        match result {
+            Err(PubGrubError::NoSolution(derivation_tree)) => {
+                assert_eq!(solver.missing_packages_in(&derivation_tree), vec!["package-formul".to_string()]);
+                assert!(explain(&solver, &derivation_tree)?.contains("package package-formul is not in the repository"));
+            }
+            _ => unreachable!(),
+        }
+
+        // packages only named by conflicts and depopts aren't blamed for a failure
+        // that has nothing to do with them, here that of conflicts-error
+        let solver = index.solver();
+        let result = solver.solve(Package::from_str("conflicts-removed").unwrap(), "1.0.0".parse::<OpamVersion>().unwrap());
+        let Err(PubGrubError::NoSolution(derivation_tree)) = result else {
+            panic!("expected conflicts-removed to have no solution");
+        };
+        assert!(!solver.missing_packages().is_empty());
+        assert!(solver.missing_packages_in(&derivation_tree).is_empty());
+        let text = explain(&solver, &derivation_tree)?;
+        assert!(!text.contains("removed-package"));
+        assert!(!text.contains("removed-depopt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_class_error() -> Result<(), Box<dyn Error>> {
+        let index = Index::new("./example-repo/packages".to_string());
+        let result = solve_index(
+            &index,
+            Package::from_str("conflict-class-error").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        );
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_class_multiple() -> Result<(), Box<dyn Error>> {
+        let index = Index::new("./example-repo/packages".to_string());
+        let sol = solve_index(
+            &index,
+            Package::from_str("conflict-class-multiple").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        assert_eq!(sol.get(&Package::ConflictClass("class".to_string())), Some(&OpamVersion("class-a".to_string())));
+        assert_eq!(sol.get(&Package::ConflictClass("other-class".to_string())), Some(&OpamVersion("class-d".to_string())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_class_multiple_error1() -> Result<(), Box<dyn Error>> {
+        let index = Index::new("./example-repo/packages".to_string());
+        let result = solve_index(
+            &index,
+            Package::from_str("conflict-class-multiple-error1").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        );
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_class_multiple_error2() -> Result<(), Box<dyn Error>> {
+        let index = Index::new("./example-repo/packages".to_string());
+        let result = solve_index(
+            &index,
+            Package::from_str("conflict-class-multiple-error2").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        );
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_class_members() -> Result<(), Box<dyn Error>> {
+        // every member is known before any of them is encoded
+        let index = Index::new("./example-repo/packages".to_string());
+        let solver = index.solver();
+        let members = |class: &str| -> Result<Vec<OpamVersion>, Box<dyn Error>> {
+            Ok(solver.list_versions(&Package::ConflictClass(class.to_string()))?.collect())
+        };
+        assert_eq!(
+            members("class")?,
+            vec![
+                OpamVersion("class-a".to_string()),
+                OpamVersion("class-b".to_string()),
+                OpamVersion("class-c".to_string()),
+            ]
+        );
+        assert_eq!(
+            members("other-class")?,
+            vec![OpamVersion("class-c".to_string()), OpamVersion("class-d".to_string())]
+        );
+        assert!(members("no-such-class")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_class_scan_error() -> Result<(), Box<dyn Error>> {
+        // malformed.2.0.0 can't be read, so it's left out of the scan rather than failing it
+        let solver = Index::new("./example-repo/packages".to_string()).solver();
+        let members: Vec<OpamVersion> = solver.list_versions(&Package::ConflictClass("class".to_string()))?.collect();
+        assert_eq!(members.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_explanation() -> Result<(), Box<dyn Error>> {
+        let index = Index::new("./example-repo/packages".to_string());
+        let explain_json = |root: Package| -> Result<serde_json::Value, Box<dyn Error>> {
+            let solver = index.solver();
+            let version = match root {
+                Package::Root(_) => OpamVersion("".to_string()),
+                _ => "1.0.0".parse::<OpamVersion>()?,
+            };
+            let result = solver.solve(root, version);
+            Ok(serde_json::to_value(JsonDocument::new(&solver, &result))?)
+        };
+
+        // conflicts-error depends on A, which needs D, and conflicts with D
+        let json = explain_json(Package::from_str("conflicts-error").unwrap())?;
+        assert_eq!(json["version"], JSON_VERSION);
+        assert_eq!(json["result"], "no-solution");
+        let root = &json["nodes"][json["root"].as_u64().unwrap() as usize];
+        assert_eq!(root["kind"], "derived");
+        assert_eq!(root["description"], "conflicts-error = 1.0.0 is forbidden");
+        let conflict = root["causes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|cause| &json["nodes"][cause.as_u64().unwrap() as usize])
+            .find(|cause| cause["kind"] == "dependency")
+            .unwrap();
+        assert_eq!(conflict["terms"][1]["package"], "D");
+        assert_eq!(conflict["origin"]["package"], "conflicts-error");
+        assert!(conflict["origin"]["file"]
+            .as_str()
+            .unwrap()
+            .ends_with("conflicts-error/conflicts-error.1.0.0/opam.json"));
+        assert_eq!(json["missing"], serde_json::json!([]));
+
+        let json = explain_json(Package::from_str("missing-package").unwrap())?;
+        assert_eq!(json["missing"][0]["package"], "package-formul");
+        assert_eq!(json["missing"][0]["suggestions"][0], "package-formula");
+        let json = explain_json(Package::from_str("conflicts-removed").unwrap())?;
+        assert_eq!(json["result"], "no-solution");
+        assert_eq!(json["missing"], serde_json::json!([]));
+
+        // a solution and an error are documents too
+        let json = explain_json(Package::Root(vec![(Package::Base("F".to_string()), Range::full())]))?;
+        assert_eq!(json["version"], JSON_VERSION);
+        assert_eq!(json["result"], "solved");
+        assert_eq!(json["packages"][0]["name"], "D");
+        assert_eq!(json["packages"][0]["version"], "3.0.0");
+        assert_eq!(json["packages"][1]["name"], "F");
+        assert_eq!(json["packages"][1]["dependencies"], serde_json::json!([{ "name": "D", "version": "3.0.0" }]));
+
+        let json = explain_json(Package::Root(vec![(Package::Base("malformed".to_string()), Range::full())]))?;
+        assert_eq!(json["result"], "error");
+        assert_eq!(json["package"], "malformed");
+        assert_eq!(json["version"], "2.0.0");
+        Ok(())
+    }
+
+    #[test]
+    fn test_origins() -> Result<(), Box<dyn Error>> {
+        let solver = Index::new("./example-repo/packages".to_string()).solver();
+        let sol = solver.solve(Package::from_str("package-formula-or").unwrap(), "1.0.0".parse::<OpamVersion>().unwrap())?;
+        let synthetic: Vec<_> = sol.iter().filter(|(package, _)| package.is_synthetic()).collect();
+        assert!(!synthetic.is_empty());
+        for (package, version) in synthetic {
+            assert_eq!(
+                solver.origin(package, version),
+                Some(("package-formula-or".to_string(), "1.0.0".parse::<OpamVersion>()?))
+            );
+        }
+
+        // the same formula in two opam files can't be blamed on either
+        let solver = Index::new("./example-repo/packages".to_string()).solver();
+        let root = Package::Root(vec![
+            (Package::Base("package-formula-or".to_string()), Range::singleton("1.0.0".parse::<OpamVersion>()?)),
+            (Package::Base("package-formula-or-shared".to_string()), Range::full()),
+        ]);
+        let sol = solver.solve(root, OpamVersion("".to_string()))?;
+        let synthetic: Vec<_> = sol.iter().filter(|(package, _)| package.is_synthetic()).collect();
+        assert!(!synthetic.is_empty());
+        for (package, version) in synthetic {
+            assert_eq!(solver.origin(package, version), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_independent_solvers() -> Result<(), Box<dyn Error>> {
+        let index = Index::new("./example-repo/packages".to_string());
+        let solver = index.solver();
+        let result = solver.solve(Package::from_str("missing-package").unwrap(), "1.0.0".parse::<OpamVersion>().unwrap());
+        assert!(result.is_err());
+        assert!(!solver.missing_packages().is_empty());
+
+        // what one solve discovers isn't seen by the next
+        let solver = index.solver();
+        solver.solve(Package::from_str("A").unwrap(), "1.0.0".parse::<OpamVersion>().unwrap())?;
+        assert!(solver.missing_packages().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Index>();
+    }
+
+    #[test]
+    fn test_concurrent_solves() -> Result<(), Box<dyn Error>> {
+        let index = Index::new("./example-repo/packages".to_string());
+        let roots = ["A", "conflicts-version", "depopts-filtered", "filtered-package-formula-variable-string"];
+        // the solves share one index
+        let solutions = std::thread::scope(|scope| {
+            let handles: Vec<_> = roots
+                .iter()
+                .map(|root| {
+                    let index = &index;
+                    scope.spawn(move || {
+                        index.solve(Package::from_str(root).unwrap(), "1.0.0".parse::<OpamVersion>().unwrap()).ok()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect::<Vec<_>>()
+        });
+        for (root, solution) in roots.iter().zip(solutions) {
+            let expected = index.solve(Package::from_str(root).unwrap(), "1.0.0".parse::<OpamVersion>().unwrap())?;
+            assert_eq!(solution, Some(expected));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect_host() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        // the detected values overwrite those loaded earlier
+        index.load_profile(Path::new("./example-repo/profiles/macos-arm64"))?;
+        index.set_variable("os", "haiku");
+        index.detect_host();
+        assert_eq!(index.variable("os"), Some(OpamVersion(normalise_os(std::env::consts::OS))));
+        assert_eq!(index.variable("arch"), Some(OpamVersion(normalise_arch(std::env::consts::ARCH))));
+
+        // and values set afterwards take precedence
+        index.set_variable("os", "macos");
+        index.set_variable("arch", "arm64");
+        let sol = solve_index(
+            &index,
+            Package::from_str("available").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        assert_eq!(sol.get(&Package::from_str("available").unwrap()), Some("1.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_declared_domain() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.declare_domain("os-family", &["arch"]);
+        let sol = solve_index(
+            &index,
+            Package::from_str("filtered-package-formula-variable-string").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        assert_eq!(sol.get(&Package::Var("os-family".to_string())), Some("arch".parse::<OpamVersion>().as_ref().unwrap()));
+        assert_eq!(sol.get(&Package::Base("A".to_string())), None);
+
+        index.declare_domain("os-family", &["debian"]);
+        let sol = solve_index(
+            &index,
+            Package::from_str("filtered-package-formula-variable-string").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        assert_eq!(sol.get(&Package::Var("os-family".to_string())), Some("debian".parse::<OpamVersion>().as_ref().unwrap()));
+        assert!(sol.get(&Package::Base("A".to_string())).is_some_and(|version| *version != *NOT_INSTALLED_VERSION));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_domains() -> Result<(), Box<dyn Error>> {
+        // malformed.2.0.0 can't be read, so it's skipped whatever the error policy
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.declare_domain("os", &["linux", "macos"]);
+        index.scan_domains()?;
+        assert_eq!(index.solver().domain("os"), vec![OpamVersion("linux".to_string()), OpamVersion("macos".to_string())]);
+        assert!(index.solver().domain("arch").contains(&OpamVersion("x86_64".to_string())));
+        assert!(index.solver().domain("os-family").contains(&OpamVersion("debian".to_string())));
+        assert_eq!(index.solver().domain("with-test"), vec![FALSE_VERSION.clone(), TRUE_VERSION.clone()]);
+
+        // a repository that can't be scanned is an error for the caller
+        let mut index = Index::new("./no-such-repo/packages".to_string());
+        assert!(index.scan_domains().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_deterministic_solves() -> Result<(), Box<dyn Error>> {
+        let solve = || {
+            let mut index = Index::new("./example-repo/packages".to_string());
+            index.scan_domains().unwrap();
+            let root = Package::Root(vec![
+                (Package::Base("available".to_string()), Range::full()),
+                (Package::Base("filtered-package-formula-variable-string".to_string()), Range::full()),
+            ]);
+            solve_index(&index, root, OpamVersion("".to_string())).unwrap()
+        };
+        let first = solve();
+        for _ in 0..5 {
+            assert_eq!(solve(), first);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_prioritizers() -> Result<(), Box<dyn Error>> {
+        for prioritizer in PRIORITIZERS {
+            let mut index = Index::new("./example-repo/packages".to_string());
+            index.set_prioritizer(prioritizer);
+            let sol = solve_index(&index, Package::from_str("A").unwrap(), "1.0.0".parse::<OpamVersion>().unwrap())?;
+            assert!(sol.contains_key(&Package::from_str("D").unwrap()));
+            let result = solve_index(&index, Package::from_str("conflict-class-error").unwrap(), "1.0.0".parse::<OpamVersion>().unwrap());
+            assert!(result.is_err());
+        }
+
+        // the prioritizer changes the order packages are decided in
+        let path = std::env::temp_dir().join("pubgrub-opam-prioritizers");
+        generate_repository(&path, 60, 6)?;
+        let repository = Arc::new(Repository::new(path.to_string_lossy().to_string()));
+        let root = Package::Root(
+            (50..60)
+                .map(|package| (Package::Base(format!("p{}", package)), Range::full()))
+                .collect(),
+        );
+        let decide = |prioritizer: Prioritizer| {
+            let mut index = Index::with_repository(repository.clone());
+            index.set_prioritizer(prioritizer);
+            decisions(&index, root.clone())
+        };
+        let (constant, solved) = decide(Prioritizer::Constant)?;
+        assert!(solved);
+        let (conflicts, solved) = decide(Prioritizer::Conflicts { synthetic_first: false })?;
+        assert!(solved);
+        assert_ne!(conflicts, constant);
+        // a prioritizer of our own that decides the requested packages in reverse order
+        let custom = Prioritizer::Custom(Arc::new(|package, _, _| {
+            let number = match package {
+                Package::Base(name) => name[1..].parse().unwrap_or(0),
+                _ => 0,
+            };
+            (number, Reverse(0), false)
+        }));
+        let (custom, solved) = decide(custom)?;
+        assert!(solved);
+        assert_ne!(custom, constant);
+        assert_eq!(custom[1..11], (50..60).rev().map(|package| Package::Base(format!("p{}", package))).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_strategies() -> Result<(), Box<dyn Error>> {
+        let solve_d = |strategy: VersionStrategy| -> Result<Option<OpamVersion>, Box<dyn Error>> {
+            let mut index = Index::new("./example-repo/packages".to_string());
+            index.set_version_strategy(strategy);
+            let root = Package::Root(vec![(Package::Base("F".to_string()), Range::full())]);
+            let sol = solve_index(&index, root, OpamVersion("".to_string()))?;
+            Ok(sol.get(&Package::from_str("D").unwrap()).cloned())
+        };
+        let locked = |version: &str, upgrade: &[&str]| VersionStrategy::Locked {
+            versions: BTreeMap::from([("D".to_string(), version.parse::<OpamVersion>().unwrap())]),
+            upgrade: upgrade.iter().map(|package| package.to_string()).collect(),
+        };
+        assert_eq!(solve_d(VersionStrategy::Newest)?, Some("3.0.0".parse::<OpamVersion>()?));
+        // F depends on D >= 2.0.0
+        assert_eq!(solve_d(VersionStrategy::Oldest)?, Some("2.0.0".parse::<OpamVersion>()?));
+        assert_eq!(solve_d(locked("2.0.0", &[]))?, Some("2.0.0".parse::<OpamVersion>()?));
+        assert_eq!(solve_d(locked("1.0.0", &[]))?, Some("3.0.0".parse::<OpamVersion>()?));
+        assert_eq!(solve_d(locked("2.0.0", &["D"]))?, Some("3.0.0".parse::<OpamVersion>()?));
+
+        // a package to upgrade can't move below its locked version
+        let solve_d_below = |strategy: VersionStrategy| -> Result<Option<OpamVersion>, Box<dyn Error>> {
+            let mut index = Index::new("./example-repo/packages".to_string());
+            index.set_version_strategy(strategy);
+            let root = Package::Root(vec![
+                (Package::Base("F".to_string()), Range::full()),
+                (Package::Base("D".to_string()), Range::strictly_lower_than("3.0.0".parse::<OpamVersion>()?)),
+            ]);
+            let sol = solve_index(&index, root, OpamVersion("".to_string()))?;
+            Ok(sol.get(&Package::from_str("D").unwrap()).cloned())
+        };
+        assert_eq!(solve_d_below(locked("3.0.0", &[]))?, Some("2.0.0".parse::<OpamVersion>()?));
+        assert_eq!(solve_d_below(locked("1.0.0", &["D"]))?, Some("2.0.0".parse::<OpamVersion>()?));
+        assert!(solve_d_below(locked("3.0.0", &["D"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_avoid_version() -> Result<(), Box<dyn Error>> {
+        let solve_flagged = |range: Range<OpamVersion>| -> Result<Option<OpamVersion>, Box<dyn Error>> {
+            let index = Index::new("./example-repo/packages".to_string());
+            let root = Package::Root(vec![(Package::Base("flagged".to_string()), range)]);
+            let sol = solve_index(&index, root, OpamVersion("".to_string()))?;
+            Ok(sol.get(&Package::from_str("flagged").unwrap()).cloned())
+        };
+        // 2.0.0 is flagged avoid-version and 3.0.0 is also deprecated
+        assert_eq!(solve_flagged(Range::full())?, Some("1.0.0".parse::<OpamVersion>()?));
+        assert_eq!(
+            solve_flagged(Range::higher_than("2.0.0".parse::<OpamVersion>()?))?,
+            Some("3.0.0".parse::<OpamVersion>()?)
+        );
+
+        // a locked version is kept even if it's avoided
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.set_version_strategy(VersionStrategy::Locked {
+            versions: BTreeMap::from([("flagged".to_string(), "2.0.0".parse::<OpamVersion>()?)]),
+            upgrade: BTreeSet::new(),
+        });
+        let root = Package::Root(vec![(Package::Base("flagged".to_string()), Range::full())]);
+        let sol = solve_index(&index, root, OpamVersion("".to_string()))?;
+        assert_eq!(sol.get(&Package::from_str("flagged").unwrap()), Some(&"2.0.0".parse::<OpamVersion>()?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_deprecated() -> Result<(), Box<dyn Error>> {
+        let index = Index::new("./example-repo/packages".to_string());
+        let root = Package::Root(vec![(Package::Base("deprecated-package".to_string()), Range::full())]);
+        let sol = solve_index(&index, root, OpamVersion("".to_string()))?;
+        let version = sol.get(&Package::from_str("deprecated-package").unwrap()).unwrap();
+        assert_eq!(version, &"1.0.0".parse::<OpamVersion>()?);
+        assert!(index.is_deprecated("deprecated-package", version)?);
+        assert!(!index.is_deprecated("flagged", &"2.0.0".parse::<OpamVersion>()?)?);
+        assert!(!index.is_deprecated("A", &"1.0.0".parse::<OpamVersion>()?)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_opam_variables() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.set_variable_policy(VariablePolicy::Opam {
+            with_test: BTreeSet::new(),
+            with_doc: BTreeSet::new(),
+        });
+        let sol = solve_index(
+            &index,
+            Package::from_str("opam-variables").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        assert_eq!(sol.get(&Package::from_str("F").unwrap()), Some("2.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        for package in ["A", "B", "C"] {
+            assert_eq!(sol.get(&Package::from_str(package).unwrap()), None);
+        }
+        for var in ["with-test", "with-doc", "build", "post", "dev"] {
+            assert_eq!(sol.get(&Package::Var(var.to_string())), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_opam_variables_with_test() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.set_variable_policy(VariablePolicy::Opam {
+            with_test: BTreeSet::from(["opam-variables".to_string()]),
+            with_doc: BTreeSet::new(),
+        });
+        let sol = solve_index(
+            &index,
+            Package::from_str("opam-variables").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        assert!(sol.get(&Package::from_str("B").unwrap()).is_some_and(|version| *version != *NOT_INSTALLED_VERSION));
+        assert_eq!(sol.get(&Package::from_str("A").unwrap()), None);
+
+        // tests of dependencies aren't enabled
+        index.set_variable_policy(VariablePolicy::Opam {
+            with_test: BTreeSet::from(["F".to_string()]),
+            with_doc: BTreeSet::from(["opam-variables".to_string()]),
+        });
+        let sol = solve_index(
+            &index,
+            Package::from_str("opam-variables").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        assert!(sol.get(&Package::from_str("A").unwrap()).is_some_and(|version| *version != *NOT_INSTALLED_VERSION));
+        assert_eq!(sol.get(&Package::Var("with-test".to_string())), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_opam_variables_dev() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.set_variable_policy(VariablePolicy::Opam {
+            with_test: BTreeSet::new(),
+            with_doc: BTreeSet::new(),
+        });
+        let sol = solve_index(
+            &index,
+            Package::from_str("opam-variables").unwrap(),
+            "dev".parse::<OpamVersion>().unwrap(),
+        )?;
+        assert!(sol.get(&Package::from_str("C").unwrap()).is_some_and(|version| *version != *NOT_INSTALLED_VERSION));
+        assert!(is_dev_version(&"1.0~dev".parse::<OpamVersion>().unwrap()));
+        assert!(!is_dev_version(&"1.0.0".parse::<OpamVersion>().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_package_variables() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.set_package_variable("D", "2.0.0", "native", "true");
+        index.set_package_variable("D", "3.0.0", "native", "false");
+        let root = Package::Root(vec![
+            (Package::Base("package-variables".to_string()), Range::full()),
+            (Package::Base("D".to_string()), Range::singleton("2.0.0".parse::<OpamVersion>().unwrap())),
+        ]);
+        let sol = solve_index(&index, root, OpamVersion("".to_string()))?;
+        assert!(sol.get(&Package::from_str("C").unwrap()).is_some_and(|version| *version != *NOT_INSTALLED_VERSION));
+        assert_eq!(sol.get(&Package::from_str("A").unwrap()), None);
+        assert_eq!(sol.get(&Package::Var("D:native".to_string())), None);
+
+        // without a value the variable doesn't require anything
+        let index = Index::new("./example-repo/packages".to_string());
+        let root = Package::Root(vec![
+            (Package::Base("package-variables".to_string()), Range::full()),
+            (Package::Base("D".to_string()), Range::singleton("2.0.0".parse::<OpamVersion>().unwrap())),
+        ]);
+        let sol = solve_index(&index, root, OpamVersion("".to_string()))?;
+        assert_eq!(sol.get(&Package::from_str("C").unwrap()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_package_variables_negated() -> Result<(), Box<dyn Error>> {
+        // like opam, a variable is undefined for a version without a value, so neither a
+        // filter on it nor its negation holds
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.set_package_variable("D", "2.0.0", "native", "true");
+        index.set_package_variable("D", "3.0.0", "native", "false");
+        let v1 = "1.0.0".parse::<OpamVersion>()?;
+        assert!(!index.package_variable_range("D", "native", &RelOp::Eq, &TRUE_VERSION).contains(&v1));
+        assert!(!index.package_variable_range("D", "native", &RelOp::Neq, &TRUE_VERSION).contains(&v1));
+        assert!(!index.package_variable_defined("D", "native").contains(&v1));
+        for (d, c, sibling) in [("1.0.0", false, false), ("2.0.0", true, false), ("3.0.0", false, true)] {
+            let root = Package::Root(vec![
+                (Package::Base("package-variables-negated".to_string()), Range::full()),
+                (Package::Base("D".to_string()), Range::singleton(d.parse::<OpamVersion>()?)),
+            ]);
+            let sol = solve_index(&index, root, OpamVersion("".to_string()))?;
+            assert_eq!(sol.contains_key(&Package::from_str("C").unwrap()), c, "D {}", d);
+            assert_eq!(sol.contains_key(&Package::from_str("sibling").unwrap()), sibling, "D {}", d);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_package_variables_version() -> Result<(), Box<dyn Error>> {
+        // D 3.0.0 requires A, which can't be installed alongside it
+        let root = Package::Root(vec![
+            (Package::Base("package-variables".to_string()), Range::full()),
+            (Package::Base("D".to_string()), Range::singleton("3.0.0".parse::<OpamVersion>().unwrap())),
+        ]);
+        let result = solve_repo(root, OpamVersion("".to_string()), "./example-repo/packages");
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_package_variables_absent() -> Result<(), Box<dyn Error>> {
+        // C conflicts, so its variables are undefined and neither the filter on
+        // removed-package nor its negation holds: it isn't required
+        let sol = solve_repo(
+            Package::from_str("package-variables-absent").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        )?;
+        assert!(!sol.contains_key(&Package::from_str("C").unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_package_variables_scope() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        let root = |version: &str| {
+            Package::Root(vec![(
+                Package::Base("package-variables-scope".to_string()),
+                Range::singleton(version.parse::<OpamVersion>().unwrap()),
+            )])
+        };
+        // as in opam, a variable of a package that isn't in the repository, or of its
+        // own package that we don't know, is undefined, so the filter is false
+        for version in ["1.0.0", "2.0.0", "3.0.0"] {
+            let sol = index.solve(root(version), OpamVersion("".to_string()))?;
+            assert_eq!(sol.get(&Package::from_str("D").unwrap()), None);
+        }
+
+        // `_` refers to the package's own variables
+        index.set_package_variable("package-variables-scope", "3.0.0", "native", "true");
+        let sol = index.solve(root("3.0.0"), OpamVersion("".to_string()))?;
+        assert!(sol.get(&Package::from_str("D").unwrap()).is_some_and(|version| *version != *NOT_INSTALLED_VERSION));
+        Ok(())
+    }
+
+    #[test]
+    fn test_self_reference() -> Result<(), Box<dyn Error>> {
+        for version in ["1.0.0", "2.0.0"] {
+            let sol = solve_repo(
+                Package::from_str("self-reference").unwrap(),
+                version.parse::<OpamVersion>().unwrap(),
+                "./example-repo/packages",
+            )?;
+            assert_eq!(sol.get(&Package::from_str("sibling").unwrap()), Some(version.parse::<OpamVersion>().as_ref().unwrap()));
+            assert_eq!(sol.get(&Package::from_str("A").unwrap()), None);
+            assert_eq!(sol.get(&Package::Var("version".to_string())), None);
+            assert_eq!(sol.get(&Package::Var("name".to_string())), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_relop_proxy() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.set_variable("os-version", "12");
+        index.set_variable("min-os-version", "11");
+        index.set_variable("test", "false");
+        index.set_variable("build", "false");
+        index.set_variable("doc", "true");
+        let sol = solve_index(
+            &index,
+            Package::from_str("relop-proxy").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        for package in ["sibling", "D", "F"] {
+            assert!(sol.get(&Package::from_str(package).unwrap()).is_some_and(|version| *version != *NOT_INSTALLED_VERSION));
+        }
+
+        index.set_variable("os-version", "10");
+        index.set_variable("build", "true");
+        let sol = solve_index(
+            &index,
+            Package::from_str("relop-proxy").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        for package in ["sibling", "D", "F"] {
+            assert_eq!(sol.get(&Package::from_str(package).unwrap()), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_relop_proxy_unset_variables() -> Result<(), Box<dyn Error>> {
+        // the solver picks values for the comparisons, preferring to leave packages out
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.declare_domain("os-version", &["12"]);
+        index.declare_domain("min-os-version", &["11", "13"]);
+        let sol = solve_index(
+            &index,
+            Package::from_str("relop-proxy").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        assert_eq!(sol.get(&Package::Var("min-os-version".to_string())), Some("13".parse::<OpamVersion>().as_ref().unwrap()));
+        assert_eq!(sol.get(&Package::from_str("sibling").unwrap()), None);
+        assert!(sol.get(&Package::from_str("D").unwrap()).is_some_and(|version| *version != *NOT_INSTALLED_VERSION));
+        Ok(())
+    }
+
+    #[test]
+    fn test_literals() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.set_variable("build", "true");
+        index.set_variable("jobs", "16");
+        let sol = solve_index(
+            &index,
+            Package::from_str("literals").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        for package in ["sibling", "F"] {
+            assert!(sol.get(&Package::from_str(package).unwrap()).is_some_and(|version| *version != *NOT_INSTALLED_VERSION));
+        }
+        for package in ["A", "C"] {
+            assert_eq!(sol.get(&Package::from_str(package).unwrap()), None);
+        }
+
+        index.set_variable("jobs", "2");
+        let sol = solve_index(
+            &index,
+            Package::from_str("literals").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        assert_eq!(sol.get(&Package::from_str("F").unwrap()), None);
+
+        // integers compare as versions, as in opam, which orders them numerically
+        for (jobs, installed) in [("10", true), ("1", false)] {
+            index.set_variable("jobs", jobs);
+            let sol = solve_index(
+                &index,
+                Package::from_str("literals").unwrap(),
+                "1.0.0".parse::<OpamVersion>().unwrap(),
+            )?;
+            let d = sol.get(&Package::from_str("D").unwrap());
+            assert_eq!(d.is_some_and(|version| *version != *NOT_INSTALLED_VERSION), installed);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_defined() -> Result<(), Box<dyn Error>> {
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.set_variable("foo", "true");
+        index.set_variable("os", "linux");
+        let sol = solve_index(
+            &index,
+            Package::from_str("defined").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        for package in ["sibling", "C"] {
+            assert!(sol.get(&Package::from_str(package).unwrap()).is_some_and(|version| *version != *NOT_INSTALLED_VERSION));
+        }
+        assert_eq!(sol.get(&Package::from_str("F").unwrap()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_defined_unset_variables() -> Result<(), Box<dyn Error>> {
+        // like opam, an undefined variable satisfies `!?foo` but no other filter
+        let installed = |sol: &Solution, package: &str| {
+            sol.get(&Package::from_str(package).unwrap()).is_some_and(|version| *version != *NOT_INSTALLED_VERSION)
+        };
+        let undefined_root = Package::Root(vec![
+            (Package::Base("defined".to_string()), Range::full()),
+            (Package::Var("foo".to_string()), Range::singleton(UNDEFINED_VERSION.clone())),
+        ]);
+        // only variables declared undefined, or that `scan_domains` finds tested with `?`,
+        // may be undefined
+        let mut index = Index::new("./example-repo/packages".to_string());
+        assert!(!index.may_be_undefined("foo"));
+        assert!(solve_index(&index, undefined_root.clone(), OpamVersion("".to_string())).is_err());
+        index.scan_domains()?;
+        assert!(index.may_be_undefined("foo"));
+
+        let root = Package::Root(vec![
+            (Package::Base("defined".to_string()), Range::full()),
+            (Package::Var("foo".to_string()), Range::singleton(TRUE_VERSION.clone())),
+        ]);
+        let sol = solve_index(&index, root, OpamVersion("".to_string()))?;
+        assert!(installed(&sol, "sibling"));
+        assert!(!installed(&sol, "F"));
+
+        let sol = solve_index(&index, undefined_root, OpamVersion("".to_string()))?;
+        assert!(installed(&sol, "F"));
+        assert!(!installed(&sol, "sibling"));
+        assert!(!installed(&sol, "C"));
+
+        // a variable that isn't tested with `?` is always defined
+        let sol = solve_index(
+            &index,
+            Package::from_str("filtered-package-formula-variable").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+        )?;
+        assert_ne!(sol.get(&Package::Var("build".to_string())), Some(&*UNDEFINED_VERSION));
+        Ok(())
+    }
+
+    #[test]
+    fn test_defined_absorption() -> Result<(), Box<dyn Error>> {
+        // like opam, `?(os | foo)` holds when `os` is true even if `foo` is undefined,
+        // but not when `os` is false
+        let installed = |sol: &Solution, package: &str| {
+            sol.get(&Package::from_str(package).unwrap()).is_some_and(|version| *version != *NOT_INSTALLED_VERSION)
+        };
+        let root = Package::Root(vec![
+            (Package::Base("defined".to_string()), Range::full()),
+            (Package::Var("foo".to_string()), Range::singleton(UNDEFINED_VERSION.clone())),
+        ]);
+        let mut index = Index::new("./example-repo/packages".to_string());
+        index.declare_undefined("foo");
+        index.set_variable("os", "true");
+        let sol = solve_index(&index, root.clone(), OpamVersion("".to_string()))?;
+        assert!(installed(&sol, "C"));
+
+        index.set_variable("os", "false");
+        let sol = solve_index(&index, root, OpamVersion("".to_string()))?;
+        assert!(!installed(&sol, "C"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_opam_file_without_json() -> Result<(), Box<dyn Error>> {
+        let sol = solve_repo(
+            Package::from_str("opam-only").unwrap(),
+            "1.0.0".parse::<OpamVersion>().unwrap(),
+            "./example-repo/packages",
+        )?;
+        assert_eq!(sol.get(&Package::from_str("D").unwrap()), Some("2.0.0".parse::<OpamVersion>().as_ref().unwrap()));
+        Ok(())
+    }
+
+    const PRIORITIZERS: [Prioritizer; 3] = [
+        Prioritizer::Constant,
+        Prioritizer::Conflicts { synthetic_first: false },
+        Prioritizer::Conflicts { synthetic_first: true },
+    ];
+
+    /// The deterministic random numbers `generate_repository` draws from.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) as usize % n
+        }
+    }
+
+    /// Writes a repository of `packages` packages of `versions` versions each, the same
+    /// every time, shaped like opam-repository: the first few packages are compilers,
+    /// of which every other version needs a window, newer versions needing newer
+    /// compilers, and each version also depends on a few earlier packages, mostly with
+    /// only a lower bound, sometimes on either of two, and sometimes conflicts with one.
+    /// Solving for several packages together then has to backtrack to find versions
+    /// that agree on a compiler.
+    fn generate_repository(path: &Path, packages: usize, versions: usize) -> std::io::Result<()> {
+        const COMPILERS: usize = 4;
+        let mut rng = Lcg(1);
+        let constraint = |rng: &mut Lcg| {
+            let low = 1 + rng.below(versions / 2);
+            if rng.below(4) == 0 {
+                format!("{{>= \"{}.0.0\" & < \"{}.0.0\"}}", low, low + 1 + rng.below(versions / 2))
+            } else {
+                format!("{{>= \"{}.0.0\"}}", low)
+            }
+        };
+        if path.exists() {
+            std::fs::remove_dir_all(path)?;
+        }
+        for package in 0..packages {
+            for version in 1..=versions {
+                let mut depends = vec![];
+                let mut conflicts = vec![];
+                if package >= COMPILERS {
+                    let low = 1 + (version - 1) * (versions - 3) / versions + rng.below(2);
+                    depends.push(format!(
+                        "\"p{}\" {{>= \"{}.0.0\" & < \"{}.0.0\"}}",
+                        rng.below(COMPILERS),
+                        low,
+                        low + 3
+                    ));
+                    for _ in 0..2 {
+                        let dependency = format!("\"p{}\" {}", rng.below(package), constraint(&mut rng));
+                        if rng.below(5) == 0 {
+                            let other = format!("\"p{}\" {}", rng.below(package), constraint(&mut rng));
+                            depends.push(format!("({} | {})", dependency, other));
+                        } else {
+                            depends.push(dependency);
+                        }
+                    }
+                    if rng.below(8) == 0 {
+                        let below = 1 + rng.below(versions / 2);
+                        conflicts.push(format!("\"p{}\" {{< \"{}.0.0\"}}", rng.below(package), below));
+                    }
+                }
+                let dir = path.join(format!("p{}", package)).join(format!("p{}.{}.0.0", package, version));
+                std::fs::create_dir_all(&dir)?;
+                std::fs::write(
+                    dir.join("opam"),
+                    format!(
+                        "opam-version: \"2.0\"\ndepends: [\n  {}\n]\nconflicts: [\n  {}\n]\n",
+                        depends.join("\n  "),
+                        conflicts.join("\n  "),
+                    ),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The requests `bench_prioritizers` solves in a generated repository: single
+    /// packages, and sets of packages installed together, with and without an old
+    /// compiler, which all have to agree on.
+    fn bench_requests() -> Vec<Package> {
+        let mut requests = vec![];
+        for (first, count) in [(399, 1), (300, 1), (390, 10), (290, 10)] {
+            for compiler in [None, Some(4), Some(2)] {
+                let mut packages: Vec<_> = (first..first + count)
+                    .map(|package| (Package::Base(format!("p{}", package)), Range::full()))
+                    .collect();
+                if let Some(compiler) = compiler {
+                    let old = Range::strictly_lower_than(OpamVersion(format!("{}.0.0", compiler)));
+                    packages.push((Package::Base("p0".to_string()), old));
+                }
+                requests.push(Package::Root(packages));
+            }
+        }
+        requests
+    }
+
+    /// A solver that records the packages PubGrub decides versions of, in order.
+    struct Recording<'a> {
+        solver: &'a Solver,
+        decisions: std::cell::RefCell<Vec<Package>>,
+    }
+
+    impl DependencyProvider for Recording<'_> {
+        type P = Package;
+        type V = OpamVersion;
+        type VS = Range<OpamVersion>;
+        type M = String;
+        type Err = pubgrub_opam::error::Error;
+        type Priority = Priority;
+
+        fn prioritize(
+            &self,
+            package: &Package,
+            range: &Range<OpamVersion>,
+            statistics: &pubgrub::PackageResolutionStatistics,
+        ) -> Self::Priority {
+            self.solver.prioritize(package, range, statistics)
+        }
+
+        fn choose_version(&self, package: &Package, range: &Range<OpamVersion>) -> Result<Option<OpamVersion>, Self::Err> {
+            self.decisions.borrow_mut().push(package.clone());
+            self.solver.choose_version(package, range)
+        }
+
+        fn get_dependencies(
+            &self,
+            package: &Package,
+            version: &OpamVersion,
+        ) -> Result<Dependencies<Package, Range<OpamVersion>, String>, Self::Err> {
+            self.solver.get_dependencies(package, version)
+        }
+    }
+
+    /// The packages decided on solving for a root, in order, and whether it has a solution.
+    fn decisions(index: &Index, root: Package) -> Result<(Vec<Package>, bool), Box<dyn Error>> {
+        let solver = index.solver();
+        let recording = Recording { solver: &solver, decisions: std::cell::RefCell::new(vec![]) };
+        let solved = match pubgrub::resolve(&recording, root, OpamVersion("".to_string())) {
+            Ok(_) => true,
+            Err(PubGrubError::NoSolution(_)) => false,
+            Err(err) => return Err(err.to_string().into()),
+        };
+        Ok((recording.decisions.into_inner(), solved))
+    }
+
+    // run with `cargo test --release bench_prioritizers -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_prioritizers() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join("pubgrub-opam-bench");
+        generate_repository(&path, 400, 12)?;
+        let repository = Arc::new(Repository::new(path.to_string_lossy().to_string()));
+        // read every opam file up front so that they aren't timed with the first solve
+        repository.variable_values()?;
+        let mut totals = vec![(0, std::time::Duration::ZERO); PRIORITIZERS.len()];
+        for root in bench_requests() {
+            println!("{}", root_description(&root));
+            for (prioritizer, total) in PRIORITIZERS.into_iter().zip(totals.iter_mut()) {
+                let mut index = Index::with_repository(repository.clone());
+                index.set_prioritizer(prioritizer.clone());
+                let start = Instant::now();
+                let (decisions, solved) = decisions(&index, root.clone())?;
+                let (decisions, elapsed) = (decisions.len(), start.elapsed());
+                println!("  {:?}: {} decisions, {:?}, solved: {}", prioritizer, decisions, elapsed, solved);
+                total.0 += decisions;
+                total.1 += elapsed;
+            }
+        }
+        for (prioritizer, (decisions, elapsed)) in PRIORITIZERS.into_iter().zip(totals) {
+            println!("{:?}: {} decisions, {:?}", prioritizer, decisions, elapsed);
+        }
+        Ok(())
+    }
+
+    fn root_description(root: &Package) -> String {
+        match root {
+            Package::Root(packages) => packages
+                .iter()
+                .map(|(package, range)| format!("{} {}", package, range))
+                .collect::<Vec<_>>()
+                .join(", "),
+            _ => root.to_string(),
+        }
+    }
+
     #[test]
     fn test_opam_repository_dune() -> Result<(), Box<dyn Error>> {
         solve_repo(
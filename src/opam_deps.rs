@@ -1,12 +1,17 @@
-use crate::index::{Binary, Index, PackageFormula, VersionFormula};
+use crate::error::{Error, ErrorKind, ErrorPolicy};
+use crate::index::{
+    split_package_variable, Binary, HashedRange, PackageFormula, Prioritizer, Priority, Solver,
+    Substituted, VersionFormula,
+};
 use crate::opam_version::OpamVersion;
-use crate::parse::{negate_relop, parse_dependencies_for_package_version, relop_to_range, RelOp};
+use crate::parse::{
+    flip_relop, negate_literal, negate_relop, normalize_negation, relop_to_range, RelOp,
+};
 use core::fmt::Display;
 use pubgrub::{Dependencies, DependencyConstraints, DependencyProvider, Map, Range};
-use std::collections::{HashMap, HashSet};
-use std::convert::Infallible;
+use std::cmp::Reverse;
 use std::str::FromStr;
-use std::sync::{LazyLock, Mutex};
+use std::sync::LazyLock;
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Package {
@@ -28,11 +33,19 @@ pub enum Package {
     Var(String),
 }
 
-static VARIABLE_CACHE: LazyLock<Mutex<HashMap<String, HashSet<OpamVersion>>>> =
-    LazyLock::new(|| Mutex::new(HashMap::new()));
-
-static CONFLICT_CLASS_CACHE: LazyLock<Mutex<HashMap<String, HashSet<OpamVersion>>>> =
-    LazyLock::new(|| Mutex::new(HashMap::new()));
+impl Package {
+    /// Whether this is one of the packages that only exist to encode formulas, rather
+    /// than a package in the repository, a variable or the root.
+    pub fn is_synthetic(&self) -> bool {
+        matches!(
+            self,
+            Package::ConflictClass(_)
+                | Package::Lor { .. }
+                | Package::Formula { .. }
+                | Package::Proxy { .. }
+        )
+    }
+}
 
 impl FromStr for Package {
     type Err = String;
@@ -69,40 +82,85 @@ pub static TRUE_VERSION: LazyLock<OpamVersion> = LazyLock::new(|| OpamVersion("t
 pub static FALSE_VERSION: LazyLock<OpamVersion> =
     LazyLock::new(|| OpamVersion("false".to_string()));
 
-impl Index {
-    pub fn list_versions(&self, package: &Package) -> impl Iterator<Item = OpamVersion> + '_ {
-        let versions = match package {
-            Package::Root(_) => vec![OpamVersion("".to_string())],
-            Package::Base(pkg) => self.available_versions(pkg),
-            Package::ConflictClass(pkg) => CONFLICT_CLASS_CACHE
-                .lock()
-                .unwrap()
-                .get(pkg)
-                .unwrap()
-                .iter()
-                .cloned()
-                .collect(),
-            Package::Lor { lhs: _, rhs: _ } => vec![LHS_VERSION.clone(), RHS_VERSION.clone()],
-            Package::Var(var) => match var.as_str() {
-                "os" => vec![OpamVersion("macos".to_string())],
-                "arch" => vec![OpamVersion("arm64".to_string())],
-                _ => match VARIABLE_CACHE.lock().unwrap().get(var) {
-                    Some(m) => m.iter().cloned().collect(),
-                    None => vec![FALSE_VERSION.clone(), TRUE_VERSION.clone()],
-                },
-            },
-            Package::Formula {
-                name: _,
-                formula: _,
-            } => vec![FALSE_VERSION.clone(), TRUE_VERSION.clone()],
-            Package::Proxy {
-                name: _,
-                formula: _,
-            } => vec![LHS_VERSION.clone(), RHS_VERSION.clone()],
-        };
-        if self.version_debug.get() {
+// a pseudo-version of every `Package::Base`, selected when the package is not part of the
+// solution, so that conflicts can mention a package without forcing it to be installed;
+// `index::installed` leaves it out of solutions
+pub static NOT_INSTALLED_VERSION: LazyLock<OpamVersion> =
+    LazyLock::new(|| OpamVersion("not installed".to_string()));
+
+// a pseudo-value of every variable the solver chooses, selected when it is undefined
+pub static UNDEFINED_VERSION: LazyLock<OpamVersion> =
+    LazyLock::new(|| OpamVersion("not defined".to_string()));
+
+/// The values of a `Package::Var` for which it is defined.
+pub fn defined_range() -> Range<OpamVersion> {
+    Range::singleton(UNDEFINED_VERSION.clone()).complement()
+}
+
+/// The versions of a `Package::Base` that correspond to it actually being installed.
+pub fn installed_range() -> Range<OpamVersion> {
+    Range::singleton(NOT_INSTALLED_VERSION.clone()).complement()
+}
+
+impl Solver {
+    /// Evaluates a filter as far as the variable environment allows, returning `None`
+    /// if it depends on variables that are left to the solver.
+    fn evaluate_filter(&self, formula: &VersionFormula) -> Option<bool> {
+        match formula {
+            VersionFormula::Variable(var) => self.index.variable(var).map(|v| v == *TRUE_VERSION),
+            VersionFormula::Not(var) => self.index.variable(var).map(|v| v == *FALSE_VERSION),
+            // a variable in the environment is defined, but others may not be
+            VersionFormula::Defined(var) => self.index.variable(var).map(|_| true),
+            VersionFormula::Undefined(var) => self.index.variable(var).map(|_| false),
+            VersionFormula::And(Binary { lhs, rhs }) => {
+                match (self.evaluate_filter(lhs), self.evaluate_filter(rhs)) {
+                    (Some(false), _) | (_, Some(false)) => Some(false),
+                    (Some(true), Some(true)) => Some(true),
+                    _ => None,
+                }
+            }
+            VersionFormula::Or(Binary { lhs, rhs }) => {
+                match (self.evaluate_filter(lhs), self.evaluate_filter(rhs)) {
+                    (Some(true), _) | (_, Some(true)) => Some(true),
+                    (Some(false), Some(false)) => Some(false),
+                    _ => None,
+                }
+            }
+            VersionFormula::Comparator { relop, binary } => {
+                let value = |operand: &VersionFormula| match operand {
+                    VersionFormula::Lit(lit) => Some(lit.clone()),
+                    VersionFormula::Variable(var) => self.index.variable(var),
+                    _ => None,
+                };
+                Some(relop_to_range(relop, value(&binary.rhs)?).contains(&value(&binary.lhs)?))
+            }
+            _ => None,
+        }
+    }
+
+    /// The values a variable can take: its value in the environment, or else its domain
+    /// and, least preferred, being undefined if it may be.
+    fn variable_values(&self, var: &str) -> Vec<OpamVersion> {
+        match self.index.variable(var) {
+            Some(value) => vec![value],
+            None => {
+                let mut values = self.domain(var);
+                if self.index.may_be_undefined(var) {
+                    values.push(UNDEFINED_VERSION.clone());
+                }
+                values
+            }
+        }
+    }
+
+    pub fn list_versions(
+        &self,
+        package: &Package,
+    ) -> Result<impl Iterator<Item = OpamVersion> + '_, Error> {
+        let versions = self.package_versions(package)?;
+        if self.index.version_debug {
             print!("versions of {}", package);
-            if versions.len() > 0 {
+            if !versions.is_empty() {
                 print!(": ")
             }
             let mut first = true;
@@ -115,11 +173,84 @@ impl Index {
             }
             println!();
         }
-        versions.into_iter()
+        Ok(versions.into_iter())
+    }
+
+    /// The versions of a package, in order of preference.
+    fn package_versions(&self, package: &Package) -> Result<Vec<OpamVersion>, Error> {
+        let versions = match package {
+            Package::Root(_) => vec![OpamVersion("".to_string())],
+            // prefer leaving out packages nothing requires
+            Package::Base(pkg) => {
+                let available = match self.available_versions(pkg) {
+                    Ok(available) => available,
+                    Err(err) => match self.index.error_policy {
+                        ErrorPolicy::Fail => return Err(err),
+                        // the package can still be left out of the solution
+                        ErrorPolicy::Unavailable => {
+                            if self.index.debug {
+                                println!("{}", err);
+                            }
+                            vec![]
+                        }
+                    },
+                };
+                std::iter::once(NOT_INSTALLED_VERSION.clone())
+                    .chain(self.index.prefer_versions(pkg, available)?)
+                    .collect()
+            }
+            Package::ConflictClass(pkg) => self
+                .index
+                .repository
+                .conflict_classes()?
+                .get(pkg)
+                .map(|packages| packages.iter().cloned().collect())
+                .unwrap_or_default(),
+            Package::Lor { lhs: _, rhs: _ } => vec![LHS_VERSION.clone(), RHS_VERSION.clone()],
+            Package::Var(var) => self.variable_values(var),
+            Package::Formula {
+                name: _,
+                formula: _,
+            } => vec![FALSE_VERSION.clone(), TRUE_VERSION.clone()],
+            Package::Proxy { name: _, formula } => match &**formula {
+                // the values of the left operand
+                VersionFormula::Comparator { relop: _, binary } => match &*binary.lhs {
+                    VersionFormula::Lit(lit) => vec![lit.clone()],
+                    VersionFormula::Variable(var) if split_package_variable(var).is_none() => {
+                        self.variable_values(var)
+                    }
+                    _ => vec![TRUE_VERSION.clone(), FALSE_VERSION.clone()],
+                },
+                _ => vec![LHS_VERSION.clone(), RHS_VERSION.clone()],
+            },
+        };
+        Ok(versions)
+    }
+
+    /// The number of versions of a package in a range. This is asked for every time the
+    /// range changes, so it doesn't order the versions, and those of a package in the
+    /// repository are only listed once. A package whose versions can't be listed has
+    /// none, so the error is reported as soon as possible.
+    fn versions_in_range(&self, package: &Package, range: &Range<OpamVersion>) -> usize {
+        match package {
+            Package::Base(pkg) => {
+                let installed = self
+                    .index
+                    .repository
+                    .versions(pkg)
+                    .map(|versions| versions.iter().filter(|v| range.contains(v)).count())
+                    .unwrap_or(0);
+                installed + usize::from(range.contains(&NOT_INSTALLED_VERSION))
+            }
+            _ => self
+                .package_versions(package)
+                .map(|versions| versions.iter().filter(|v| range.contains(v)).count())
+                .unwrap_or(0),
+        }
     }
 }
 
-impl DependencyProvider for Index {
+impl DependencyProvider for Solver {
     type P = Package;
 
     type V = OpamVersion;
@@ -128,17 +259,29 @@ impl DependencyProvider for Index {
 
     type M = String;
 
-    type Err = Infallible;
+    type Err = Error;
 
-    type Priority = u8;
+    type Priority = Priority;
 
     fn prioritize(
         &self,
-        _package: &Self::P,
-        _range: &Self::VS,
-        _package_conflicts_counts: &pubgrub::PackageResolutionStatistics,
+        package: &Self::P,
+        range: &Self::VS,
+        package_conflicts_counts: &pubgrub::PackageResolutionStatistics,
     ) -> Self::Priority {
-        1
+        match &self.index.prioritizer {
+            Prioritizer::Constant => (0, Reverse(0), false),
+            Prioritizer::Conflicts { synthetic_first } => (
+                package_conflicts_counts.conflict_count(),
+                Reverse(self.versions_in_range(package, range)),
+                package.is_synthetic() == *synthetic_first,
+            ),
+            Prioritizer::Custom(prioritize) => prioritize(
+                package,
+                self.versions_in_range(package, range),
+                package_conflicts_counts.conflict_count(),
+            ),
+        }
     }
 
     fn choose_version(
@@ -146,10 +289,7 @@ impl DependencyProvider for Index {
         package: &Self::P,
         range: &Self::VS,
     ) -> Result<Option<Self::V>, Self::Err> {
-        Ok(self
-            .list_versions(package)
-            .filter(|v| range.contains(v))
-            .next())
+        Ok(self.list_versions(package)?.find(|v| range.contains(v)))
     }
 
     fn get_dependencies(
@@ -157,19 +297,123 @@ impl DependencyProvider for Index {
         package: &Package,
         version: &OpamVersion,
     ) -> Result<Dependencies<Self::P, Self::VS, Self::M>, Self::Err> {
+        match self.encode_dependencies(package, version) {
+            Ok(deps) => {
+                if let Dependencies::Available(constraints) = &deps {
+                    self.record_parents(package, version, constraints);
+                }
+                Ok(deps)
+            }
+            Err(err) => {
+                let err = err.with_package(&package.to_string(), version);
+                match self.index.error_policy {
+                    ErrorPolicy::Fail => Err(err),
+                    ErrorPolicy::Unavailable => {
+                        if self.index.debug {
+                            println!("({}, {}) unavailable: {}", package, version, err);
+                        }
+                        Ok(Dependencies::Unavailable(err.to_string()))
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Solver {
+    /// Remembers which package versions the synthetic packages among the dependencies
+    /// of a package version come from, so that conflicts can be reported against the
+    /// opam file they were encoded from.
+    fn record_parents(
+        &self,
+        package: &Package,
+        version: &OpamVersion,
+        constraints: &DependencyConstraints<Package, Range<OpamVersion>>,
+    ) {
+        let mut parents = self.parents.borrow_mut();
+        for dependency in constraints.keys().filter(|dependency| dependency.is_synthetic()) {
+            parents
+                .entry(dependency.clone())
+                .or_default()
+                .insert((package.clone(), version.clone()));
+        }
+    }
+
+    /// Encodes the formulas of a package version, remembering the dependencies that
+    /// only its conflicts constrain.
+    fn encode_formulas_of(
+        &self,
+        package: &Package,
+        version: &OpamVersion,
+        formulas: &[PackageFormula],
+    ) -> Result<DependencyConstraints<Package, Range<OpamVersion>>, ErrorKind> {
+        let (conflicts, others): (Vec<_>, Vec<_>) =
+            formulas.iter().cloned().partition(PackageFormula::is_conflict);
+        let required = self.encode_formulas(&others)?;
+        let conflicting = self.encode_formulas(&conflicts)?;
+        self.conflicts.borrow_mut().extend(
+            conflicting
+                .keys()
+                .filter(|dependency| !required.contains_key(dependency))
+                .map(|dependency| (package.clone(), version.clone(), dependency.clone())),
+        );
+        Ok(merge_constraints(required, conflicting))
+    }
+
+    fn encode_dependencies(
+        &self,
+        package: &Package,
+        version: &OpamVersion,
+    ) -> Result<Dependencies<Package, Range<OpamVersion>, String>, Error> {
         match package {
-            Package::Root(deps) => Ok(Dependencies::Available(deps.into_iter().cloned().collect())),
+            Package::Root(deps) => Ok(Dependencies::Available(
+                deps.iter()
+                    .map(|(package, range)| match package {
+                        Package::Base(_) => (package.clone(), range.intersection(&installed_range())),
+                        _ => (package.clone(), range.clone()),
+                    })
+                    .collect(),
+            )),
+            Package::Base(_) if *version == *NOT_INSTALLED_VERSION => {
+                if self.index.debug {
+                    println!("({}, {})", package, version);
+                }
+                Ok(Dependencies::Available(Map::default()))
+            }
             Package::Base(pkg) => {
-                let formulas = parse_dependencies_for_package_version(
-                    self.repo.as_str(),
-                    pkg,
-                    version.to_string().as_str(),
-                )
-                .unwrap();
-                let deps = from_formulas(&formulas);
-                if self.debug.get() {
+                let value = |var: &str| self.index.resolve_variable(pkg, version, var);
+                let file = self.index.repository.opam_file(pkg, version)?;
+                let available = &file.available;
+                let filter = available.as_ref().map(|available| available.substitute(&value));
+                let holds = match &filter {
+                    None => Some(true),
+                    Some(Substituted::Const(b)) => Some(*b),
+                    Some(Substituted::Formula(filter)) => self.evaluate_filter(filter),
+                };
+                if let Some(available) = available {
+                    if holds == Some(false) {
+                        if self.index.debug {
+                            println!("({}, {}) unavailable: {}", package, version, available);
+                        }
+                        return Ok(Dependencies::Unavailable(format!(
+                            "not available on this platform: {}",
+                            available
+                        )));
+                    }
+                }
+                let formulas: Vec<PackageFormula> = file
+                    .dependencies
+                    .iter()
+                    .filter_map(|formula| formula.substitute(&value))
+                    .collect();
+                let mut deps = self.encode_formulas_of(package, version, &formulas)?;
+                // an undecided filter is left to the solver to pick variable values for
+                if let (None, Some(Substituted::Formula(filter))) = (holds, &filter) {
+                    deps = merge_constraints(deps, self.encode_version_formula(None, filter)?);
+                }
+                if self.index.debug {
                     print!("({}, {})", package, version);
-                    if deps.len() > 0 {
+                    if !deps.is_empty() {
                         print!(" -> ")
                     }
                     let mut first = true;
@@ -188,14 +432,14 @@ impl DependencyProvider for Index {
             Package::Lor { lhs, rhs } => {
                 let deps = match version {
                     OpamVersion(ver) => match ver.as_str() {
-                        "lhs" => from_formula(*&lhs),
-                        "rhs" => from_formula(*&rhs),
-                        _ => panic!("Unknown OR version {}", version),
+                        "lhs" => self.encode_formulas_of(package, version, &[*lhs.clone()])?,
+                        "rhs" => self.encode_formulas_of(package, version, &[*rhs.clone()])?,
+                        _ => return Err(ErrorKind::UnknownVersion(version.to_string()).into()),
                     },
                 };
-                if self.debug.get() {
+                if self.index.debug {
                     print!("({}, {})", package, version);
-                    if deps.len() > 0 {
+                    if !deps.is_empty() {
                         print!(" -> ")
                     }
                     let mut first = true;
@@ -213,16 +457,16 @@ impl DependencyProvider for Index {
             Package::Formula { name, formula } => {
                 let deps = match version {
                     OpamVersion(ver) => match ver.as_str() {
-                        "true" => from_version_formula(Some(&name), formula),
+                        "true" => self.encode_version_formula(Some(name), formula)?,
                         "false" => {
-                            from_version_formula(None, &Box::new(negate_formula(*formula.clone())))
+                            self.encode_version_formula(None, &Box::new(self.negate_formula(*formula.clone())?))?
                         }
-                        _ => panic!("Unknown Formula version {}", version),
+                        _ => return Err(ErrorKind::UnknownVersion(version.to_string()).into()),
                     },
                 };
-                if self.debug.get() {
+                if self.index.debug {
                     print!("({}, {})", package, version);
-                    if deps.len() > 0 {
+                    if !deps.is_empty() {
                         print!(" -> ")
                     }
                     let mut first = true;
@@ -238,10 +482,26 @@ impl DependencyProvider for Index {
                 Ok(Dependencies::Available(deps))
             }
             Package::Proxy { name, formula } => {
-                let deps = from_proxy_formula(name.as_ref(), version, formula);
-                if self.debug.get() {
+                let deps = match self.encode_proxy_formula(name.as_ref(), version, formula)? {
+                    Some(deps) => deps,
+                    None => {
+                        if self.index.debug {
+                            println!("({}, {}) can't hold", package, version);
+                        }
+                        return Ok(Dependencies::Unavailable(format!(
+                            "{} can't hold with {} = {}",
+                            formula,
+                            match &**formula {
+                                VersionFormula::Comparator { relop: _, binary } => &binary.lhs,
+                                _ => formula,
+                            },
+                            version
+                        )));
+                    }
+                };
+                if self.index.debug {
                     print!("({}, {})", package, version);
-                    if deps.len() > 0 {
+                    if !deps.is_empty() {
                         print!(" -> ")
                     }
                     let mut first = true;
@@ -257,7 +517,7 @@ impl DependencyProvider for Index {
                 Ok(Dependencies::Available(deps))
             }
             Package::Var(_) => {
-                if self.debug.get() {
+                if self.index.debug {
                     println!("({}, {})", package, version);
                 }
                 Ok(Dependencies::Available(Map::default()))
@@ -266,263 +526,455 @@ impl DependencyProvider for Index {
     }
 }
 
-pub fn from_formulas(
-    formulas: &Vec<PackageFormula>,
-) -> DependencyConstraints<Package, Range<OpamVersion>> {
-    formulas
-        .iter()
-        .map(|formula| from_formula(formula))
-        .fold(Map::default(), |acc, cons| merge_constraints(acc, cons))
-}
-
-fn from_formula(formula: &PackageFormula) -> DependencyConstraints<Package, Range<OpamVersion>> {
-    match formula {
-        PackageFormula::Base { name, formula } => {
-            let mut map = Map::default();
-            match formula {
-                // in parse.rs we collapse non-filtered formula to a single version dependency
-                VersionFormula::Version(range) => {
-                    map.insert(Package::Base(name.to_string()), range.0.clone())
-                }
-                // otherwise, we need to introduce a formula packge to select variable values
-                _ => map.insert(
-                    Package::Formula {
-                        name: name.to_string(),
-                        formula: Box::new(formula.clone()),
-                    },
-                    Range::full(),
-                ),
-            };
-            map
-        }
-        PackageFormula::ConflictClass { name, package } => {
-            let mut map = Map::default();
-            map.insert(
-                Package::ConflictClass(name.to_string()),
-                Range::<OpamVersion>::singleton(OpamVersion(package.to_string())),
-            );
-            CONFLICT_CLASS_CACHE
-                .lock()
-                .unwrap()
-                .entry(name.to_string())
-                .or_insert_with(HashSet::new)
-                .insert(OpamVersion(package.to_string()));
-            map
-        }
-        PackageFormula::Or(Binary { lhs, rhs }) => {
-            let mut map = Map::default();
-            map.insert(
-                Package::Lor {
-                    lhs: lhs.clone(),
-                    rhs: rhs.clone(),
-                },
-                Range::full(),
-            );
-            map
-        }
-        PackageFormula::And(Binary { lhs, rhs }) => {
-            let left = from_formula(lhs);
-            let right = from_formula(rhs);
-            merge_constraints(left, right)
-        }
-    }
-}
-
 fn merge_constraints(
     mut left: DependencyConstraints<Package, Range<OpamVersion>>,
     right: DependencyConstraints<Package, Range<OpamVersion>>,
 ) -> DependencyConstraints<Package, Range<OpamVersion>> {
     for (pkg, range) in right {
-        left.entry(pkg.clone())
-            .and_modify(|existing| {
-                match pkg {
-                    _ => *existing = existing.intersection(&range),
-                };
-            })
+        left.entry(pkg)
+            .and_modify(|existing| *existing = existing.intersection(&range))
             .or_insert(range);
     }
     left
 }
 
-// we depend on this if we don't select a formula
-fn negate_formula(expr: VersionFormula) -> VersionFormula {
-    match expr {
-        // we strip out all versions, and only select variable values
-        VersionFormula::Version(_) => panic!("we should never get here"),
-        VersionFormula::Variable(variable) => VersionFormula::Not(variable),
-        VersionFormula::Not(variable) => VersionFormula::Variable(variable),
-        VersionFormula::And(Binary { lhs, rhs }) => match (*lhs.clone(), *rhs.clone()) {
-            // strip out versions
-            // if there's two versions, we propigate one and it will be stripped above
-            (VersionFormula::Version(_), _) => negate_formula(*rhs),
-            (_, VersionFormula::Version(_)) => negate_formula(*lhs),
-            // De Morgan’s laws
-            _ => VersionFormula::Or(Binary {
-                lhs: Box::new(negate_formula(*lhs)),
-                rhs: Box::new(negate_formula(*rhs)),
-            }),
-        },
-        VersionFormula::Or(Binary { lhs, rhs }) => match (*lhs.clone(), *rhs.clone()) {
-            // strip out versions
-            // if there's two versions, we propigate one and it will be stripped above
-            (VersionFormula::Version(_), _) => negate_formula(*rhs),
-            (_, VersionFormula::Version(_)) => negate_formula(*lhs),
-            // De Morgan’s laws
-            _ => VersionFormula::And(Binary {
-                lhs: Box::new(negate_formula(*lhs)),
-                rhs: Box::new(negate_formula(*rhs)),
-            }),
-        },
-        VersionFormula::Comparator { relop, binary } => VersionFormula::Comparator {
-            relop: negate_relop(relop),
-            binary,
-        },
-        VersionFormula::Lit(lit) => VersionFormula::Lit(lit),
+/// How suited an operand is to be the left operand of a comparison proxy, lower is
+/// better: a variable's values are its domain and a filter's are `true` and `false`, but
+/// we can't list the values of a package variable, and a literal only has one.
+fn operand_rank(operand: &VersionFormula) -> u8 {
+    match operand {
+        VersionFormula::Variable(var) if split_package_variable(var).is_some() => 2,
+        VersionFormula::Variable(_) => 0,
+        VersionFormula::Lit(_) => 3,
+        _ => 1,
     }
 }
 
-fn from_proxy_formula(
-    name: Option<&String>,
-    version: &OpamVersion,
-    formula: &VersionFormula,
-) -> DependencyConstraints<Package, Range<OpamVersion>> {
-    // let mut map = Map::default();
-    match formula {
-        VersionFormula::Or(Binary { lhs, rhs }) => match version {
-            OpamVersion(ver) => match ver.as_str() {
-                "lhs" => from_version_formula(name, lhs),
-                "rhs" => from_version_formula(name, rhs),
-                _ => panic!("Unknown Formula version {}", version),
-            },
-        },
-        VersionFormula::Comparator { relop, binary } => match relop {
-            RelOp::Eq => match version {
-                OpamVersion(ver) => match ver.as_str() {
-                    "lhs" => {
-                        let lhs = from_version_formula(name, &*binary.lhs);
-                        let rhs = from_version_formula(name, &*binary.rhs);
-                        merge_constraints(lhs, rhs)
-                    }
-                    "rhs" => {
-                        let lhs = from_version_formula(name, &negate_formula(*binary.lhs.clone()));
-                        let rhs = from_version_formula(name, &negate_formula(*binary.rhs.clone()));
-                        merge_constraints(lhs, rhs)
-                    }
-                    _ => panic!("Unknown Formula version {}", version),
-                },
-            },
-            RelOp::Neq => match version {
-                OpamVersion(ver) => match ver.as_str() {
-                    "lhs" => {
-                        let lhs = from_version_formula(name, &*binary.lhs);
-                        let rhs = from_version_formula(name, &negate_formula(*binary.rhs.clone()));
-                        merge_constraints(lhs, rhs)
-                    }
-                    "rhs" => {
-                        let lhs = from_version_formula(name, &negate_formula(*binary.lhs.clone()));
-                        let rhs = from_version_formula(name, &*binary.rhs);
-                        merge_constraints(lhs, rhs)
+impl Solver {
+    /// `formula`, or any of the variables it compares that may be undefined being so.
+    /// The variables of a package, other than `installed`, are undefined when it isn't
+    /// installed or has no value for them.
+    fn or_undefined(&self, formula: VersionFormula, operands: &[&VersionFormula]) -> VersionFormula {
+        operands
+            .iter()
+            .filter_map(|operand| match operand {
+                VersionFormula::Variable(var) => match split_package_variable(var) {
+                    Some((_, "installed")) => None,
+                    Some(_) => Some(VersionFormula::Undefined(var.clone())),
+                    None if self.index.may_be_undefined(var) => {
+                        Some(VersionFormula::Undefined(var.clone()))
                     }
-                    _ => panic!("Unknown Formula version {}", version),
+                    None => None,
                 },
+                _ => None,
+            })
+            .fold(formula, |formula, undefined| {
+                VersionFormula::Or(Binary {
+                    lhs: Box::new(formula),
+                    rhs: Box::new(undefined),
+                })
+            })
+    }
+
+    // we depend on this if we don't select a formula, which includes when it has an
+    // undefined variable, as opam treats an undefined filter as false
+    fn negate_formula(&self, expr: VersionFormula) -> Result<VersionFormula, ErrorKind> {
+        Ok(match expr {
+            // we strip out all versions, and only select variable values
+            VersionFormula::Version(_) => {
+                return Err(ErrorKind::UnexpectedFormula(expr.to_string()))
+            }
+            VersionFormula::Variable(variable) => self.or_undefined(
+                VersionFormula::Not(variable.clone()),
+                &[&VersionFormula::Variable(variable)],
+            ),
+            VersionFormula::Not(variable) => self.or_undefined(
+                VersionFormula::Variable(variable.clone()),
+                &[&VersionFormula::Variable(variable)],
+            ),
+            VersionFormula::Defined(variable) => VersionFormula::Undefined(variable),
+            VersionFormula::Undefined(variable) => VersionFormula::Defined(variable),
+            VersionFormula::And(Binary { lhs, rhs }) => match (*lhs.clone(), *rhs.clone()) {
+                // strip out versions
+                // if there's two versions, we propigate one and it will be stripped above
+                (VersionFormula::Version(_), _) => self.negate_formula(*rhs)?,
+                (_, VersionFormula::Version(_)) => self.negate_formula(*lhs)?,
+                // De Morgan’s laws
+                _ => VersionFormula::Or(Binary {
+                    lhs: Box::new(self.negate_formula(*lhs)?),
+                    rhs: Box::new(self.negate_formula(*rhs)?),
+                }),
             },
-            _ => match name {
-                Some(name) => panic!("invalid operator for {}: {}", name, formula),
-                None => panic!("invalid operator for {}", formula),
+            VersionFormula::Or(Binary { lhs, rhs }) => match (*lhs.clone(), *rhs.clone()) {
+                // strip out versions
+                // if there's two versions, we propigate one and it will be stripped above
+                (VersionFormula::Version(_), _) => self.negate_formula(*rhs)?,
+                (_, VersionFormula::Version(_)) => self.negate_formula(*lhs)?,
+                // De Morgan’s laws
+                _ => VersionFormula::And(Binary {
+                    lhs: Box::new(self.negate_formula(*lhs)?),
+                    rhs: Box::new(self.negate_formula(*rhs)?),
+                }),
             },
-        },
-        _ => panic!("This formula shouldn't be in a proxy: {}", formula),
+            VersionFormula::Comparator { relop, binary } => self.or_undefined(
+                VersionFormula::Comparator {
+                    relop: negate_relop(relop),
+                    binary: binary.clone(),
+                },
+                &[&binary.lhs, &binary.rhs],
+            ),
+            VersionFormula::Lit(lit) => VersionFormula::Lit(negate_literal(lit)),
+            VersionFormula::Constraint { .. } => {
+                return Err(ErrorKind::UnexpectedFormula(expr.to_string()))
+            }
+        })
     }
-}
 
-fn from_version_formula(
-    name: Option<&String>,
-    formula: &VersionFormula,
-) -> DependencyConstraints<Package, Range<OpamVersion>> {
-    let mut map = Map::default();
-    match formula {
-        VersionFormula::Version(range) => {
-            if let Some(name) = name {
-                map.insert(Package::Base(name.to_string()), range.0.clone());
-            };
-            map
-        }
-        VersionFormula::Variable(variable) => {
-            if let Some(name) = name {
-                map.insert(Package::Base(name.to_string()), Range::full());
-            };
-            map.insert(
-                Package::Var(variable.to_string()),
-                Range::singleton(TRUE_VERSION.clone()),
-            );
-            map
-        }
-        VersionFormula::Not(variable) => {
-            if let Some(name) = name {
-                map.insert(Package::Base(name.to_string()), Range::full());
-            };
-            map.insert(
-                Package::Var(variable.to_string()),
-                Range::singleton(FALSE_VERSION.clone()),
-            );
-            map
-        }
-        VersionFormula::Or(_) => {
-            map.insert(
-                Package::Proxy {
-                    name: name.cloned(),
-                    formula: Box::new(formula.clone()),
-                },
-                Range::full(),
-            );
-            map
-        }
-        VersionFormula::And(Binary { lhs, rhs }) => {
-            let left = from_version_formula(name, lhs);
-            let right = from_version_formula(name, rhs);
-            merge_constraints(left, right)
-        }
-        VersionFormula::Comparator { relop, binary } => {
-            match (*binary.lhs.clone(), *binary.rhs.clone()) {
-                (VersionFormula::Lit(ver), VersionFormula::Variable(var)) => {
-                    VARIABLE_CACHE
-                        .lock()
-                        .unwrap()
-                        .entry(var.to_string())
-                        .or_insert_with(HashSet::new)
-                        .insert(ver.clone());
-                    let range = relop_to_range(relop, ver);
-                    map.insert(Package::Var(var.to_string()), range)
-                }
-                (VersionFormula::Variable(var), VersionFormula::Lit(ver)) => {
-                    VARIABLE_CACHE
-                        .lock()
-                        .unwrap()
-                        .entry(var.to_string())
-                        .or_insert_with(HashSet::new)
-                        .insert(ver.clone());
-                    let range = relop_to_range(relop, ver);
-                    map.insert(Package::Var(var.to_string()), range)
+    // a conflict's formula doesn't hold when the version is outside its range, or when
+    // a filter is false or undefined
+    fn negate_conflict(&self, expr: VersionFormula) -> Result<VersionFormula, ErrorKind> {
+        Ok(match expr {
+            VersionFormula::Version(_) | VersionFormula::Constraint { .. } => normalize_negation(expr),
+            // De Morgan’s laws
+            VersionFormula::And(Binary { lhs, rhs }) => VersionFormula::Or(Binary {
+                lhs: Box::new(self.negate_conflict(*lhs)?),
+                rhs: Box::new(self.negate_conflict(*rhs)?),
+            }),
+            VersionFormula::Or(Binary { lhs, rhs }) => VersionFormula::And(Binary {
+                lhs: Box::new(self.negate_conflict(*lhs)?),
+                rhs: Box::new(self.negate_conflict(*rhs)?),
+            }),
+            _ => self.negate_formula(expr)?,
+        })
+    }
+
+    pub fn encode_formulas(
+        &self,
+        formulas: &[PackageFormula],
+    ) -> Result<DependencyConstraints<Package, Range<OpamVersion>>, ErrorKind> {
+        formulas
+            .iter()
+            .map(|formula| self.encode_formula(formula))
+            .try_fold(Map::default(), |acc, cons| Ok(merge_constraints(acc, cons?)))
+    }
+
+    fn encode_formula(
+        &self,
+        formula: &PackageFormula,
+    ) -> Result<DependencyConstraints<Package, Range<OpamVersion>>, ErrorKind> {
+        Ok(match formula {
+            PackageFormula::Base { name, formula } => {
+                let mut map = Map::default();
+                match formula {
+                    // in parse.rs we collapse non-filtered formula to a single version dependency
+                    VersionFormula::Version(range) => map.insert(
+                        Package::Base(name.to_string()),
+                        range.0.intersection(&installed_range()),
+                    ),
+                    // otherwise, we need to introduce a formula packge to select variable values,
+                    // which requires the package to be installed when selected
+                    _ => map.insert(
+                        Package::Formula {
+                            name: name.to_string(),
+                            formula: Box::new(VersionFormula::And(Binary {
+                                lhs: Box::new(formula.clone()),
+                                rhs: Box::new(VersionFormula::Version(HashedRange(installed_range()))),
+                            })),
+                        },
+                        Range::full(),
+                    ),
+                };
+                map
+            }
+            PackageFormula::Conflict { name, formula } => match formula {
+                // the package is either not installed or outside the conflicting range
+                VersionFormula::Version(range) => {
+                    let mut map = Map::default();
+                    map.insert(
+                        Package::Base(name.to_string()),
+                        range.0.intersection(&installed_range()).complement(),
+                    );
+                    map
                 }
-                _ => match relop {
-                    RelOp::Eq | RelOp::Neq => map.insert(
-                        Package::Proxy {
-                            name: name.cloned(),
-                            formula: Box::new(formula.clone()),
+                // otherwise, the package is either not installed or the formula doesn't hold
+                _ => self.encode_version_formula(
+                    Some(name),
+                    &VersionFormula::Or(Binary {
+                        lhs: Box::new(VersionFormula::Version(HashedRange(Range::singleton(
+                            NOT_INSTALLED_VERSION.clone(),
+                        )))),
+                        rhs: Box::new(self.negate_conflict(formula.clone())?),
+                    }),
+                )?,
+            },
+            PackageFormula::Depopt { name, formula } => {
+                let mut map = Map::default();
+                let not_installed = Range::singleton(NOT_INSTALLED_VERSION.clone());
+                match formula {
+                    // the package is either not installed or within the range
+                    VersionFormula::Version(range) => map.insert(
+                        Package::Base(name.to_string()),
+                        range.0.intersection(&installed_range()).union(&not_installed),
+                    ),
+                    // otherwise, a formula package selects whether the filter holds, and if it
+                    // does the package is either not installed or satisfies the formula
+                    _ => map.insert(
+                        Package::Formula {
+                            name: name.to_string(),
+                            formula: Box::new(VersionFormula::Or(Binary {
+                                lhs: Box::new(VersionFormula::Version(HashedRange(not_installed))),
+                                rhs: Box::new(formula.clone()),
+                            })),
                         },
                         Range::full(),
                     ),
-                    _ => match name {
-                        Some(name) => panic!("invalid operator for {}: {}", name, formula),
-                        None => panic!("invalid operator for {}", formula),
+                };
+                map
+            }
+            PackageFormula::ConflictClass { name, package } => {
+                let mut map = Map::default();
+                map.insert(
+                    Package::ConflictClass(name.to_string()),
+                    Range::<OpamVersion>::singleton(OpamVersion(package.to_string())),
+                );
+                map
+            }
+            PackageFormula::Or(Binary { lhs, rhs }) => {
+                let mut map = Map::default();
+                map.insert(
+                    Package::Lor {
+                        lhs: lhs.clone(),
+                        rhs: rhs.clone(),
                     },
+                    Range::full(),
+                );
+                map
+            }
+            PackageFormula::And(Binary { lhs, rhs }) => {
+                let left = self.encode_formula(lhs)?;
+                let right = self.encode_formula(rhs)?;
+                merge_constraints(left, right)
+            }
+        })
+    }
+
+    /// The constraints of a version of a proxy package, or `None` if the formula can't
+    /// hold with that version.
+    fn encode_proxy_formula(
+        &self,
+        name: Option<&String>,
+        version: &OpamVersion,
+        formula: &VersionFormula,
+    ) -> Result<Option<DependencyConstraints<Package, Range<OpamVersion>>>, ErrorKind> {
+        match formula {
+            VersionFormula::Or(Binary { lhs, rhs }) => match version {
+                OpamVersion(ver) => match ver.as_str() {
+                    "lhs" => self.encode_version_formula(name, lhs).map(Some),
+                    "rhs" => self.encode_version_formula(name, rhs).map(Some),
+                    _ => Err(ErrorKind::UnknownVersion(version.to_string())),
                 },
-            };
-            map
+            },
+            // the version is the value of the left operand, and the right operand must
+            // be related to it
+            VersionFormula::Comparator { relop, binary } => {
+                let lhs = self.encode_operand(name, &binary.lhs, &RelOp::Eq, version)?;
+                let rhs = self.encode_operand(name, &binary.rhs, &flip_relop(relop), version)?;
+                Ok(lhs.zip(rhs).map(|(lhs, rhs)| merge_constraints(lhs, rhs)))
+            }
+            _ => Err(ErrorKind::UnexpectedFormula(formula.to_string())),
         }
-        VersionFormula::Lit(lit) => match name {
-            Some(name) => panic!("invalid literal for {} {{{}}}: {}", name, formula, lit),
-            None => panic!("invalid literal for {{{}}}: {}", formula, lit),
-        },
+    }
+
+    /// The constraints for an operand of a comparison to be related to `value` by
+    /// `relop`, or `None` if it can't be. Operands other than literals and variables are
+    /// filters, with the value `true` or `false`.
+    fn encode_operand(
+        &self,
+        name: Option<&String>,
+        operand: &VersionFormula,
+        relop: &RelOp,
+        value: &OpamVersion,
+    ) -> Result<Option<DependencyConstraints<Package, Range<OpamVersion>>>, ErrorKind> {
+        let range = relop_to_range(relop, value.clone());
+        let mut map = Map::default();
+        Ok(match operand {
+            VersionFormula::Lit(lit) => range.contains(lit).then_some(map),
+            VersionFormula::Variable(variable) => {
+                match split_package_variable(variable) {
+                    Some((package, var)) => map.insert(
+                        Package::Base(package.to_string()),
+                        self.index.package_variable_range(package, var, relop, value),
+                    ),
+                    None => map.insert(
+                        Package::Var(variable.to_string()),
+                        range.intersection(&defined_range()),
+                    ),
+                };
+                Some(map)
+            }
+            _ => match (range.contains(&TRUE_VERSION), range.contains(&FALSE_VERSION)) {
+                (true, true) => Some(map),
+                (true, false) => Some(self.encode_version_formula(name, operand)?),
+                (false, true) => Some(self.encode_version_formula(name, &self.negate_formula(operand.clone())?)?),
+                (false, false) => None,
+            },
+        })
+    }
+
+    fn encode_version_formula(
+        &self,
+        name: Option<&String>,
+        formula: &VersionFormula,
+    ) -> Result<DependencyConstraints<Package, Range<OpamVersion>>, ErrorKind> {
+        let mut map = Map::default();
+        Ok(match formula {
+            VersionFormula::Version(range) => {
+                if let Some(name) = name {
+                    map.insert(Package::Base(name.to_string()), range.0.clone());
+                };
+                map
+            }
+            VersionFormula::Variable(variable) => {
+                match split_package_variable(variable) {
+                    Some((package, var)) => map.insert(
+                        Package::Base(package.to_string()),
+                        self.index.package_variable_range(package, var, &RelOp::Eq, &TRUE_VERSION),
+                    ),
+                    None => map.insert(
+                        Package::Var(variable.to_string()),
+                        Range::singleton(TRUE_VERSION.clone()),
+                    ),
+                };
+                map
+            }
+            VersionFormula::Not(variable) => {
+                match split_package_variable(variable) {
+                    Some((package, var)) => map.insert(
+                        Package::Base(package.to_string()),
+                        self.index.package_variable_range(package, var, &RelOp::Neq, &TRUE_VERSION),
+                    ),
+                    None => map.insert(
+                        Package::Var(variable.to_string()),
+                        Range::singleton(FALSE_VERSION.clone()),
+                    ),
+                };
+                map
+            }
+            VersionFormula::Defined(variable) | VersionFormula::Undefined(variable) => {
+                let defined = matches!(formula, VersionFormula::Defined(_));
+                match split_package_variable(variable) {
+                    Some((package, var)) => {
+                        let range = self.index.package_variable_defined(package, var);
+                        map.insert(
+                            Package::Base(package.to_string()),
+                            if defined { range } else { range.complement() },
+                        )
+                    }
+                    None => map.insert(
+                        Package::Var(variable.to_string()),
+                        if defined {
+                            defined_range()
+                        } else {
+                            Range::singleton(UNDEFINED_VERSION.clone())
+                        },
+                    ),
+                };
+                map
+            }
+            VersionFormula::Or(_) => {
+                map.insert(
+                    Package::Proxy {
+                        name: name.cloned(),
+                        formula: Box::new(formula.clone()),
+                    },
+                    Range::full(),
+                );
+                map
+            }
+            VersionFormula::And(Binary { lhs, rhs }) => {
+                let left = self.encode_version_formula(name, lhs)?;
+                let right = self.encode_version_formula(name, rhs)?;
+                merge_constraints(left, right)
+            }
+            VersionFormula::Comparator { relop, binary } => {
+                match (*binary.lhs.clone(), *binary.rhs.clone()) {
+                    // a package variable constrains the version of its package
+                    (VersionFormula::Lit(ver), VersionFormula::Variable(var))
+                        if split_package_variable(&var).is_some() =>
+                    {
+                        let (package, var) = split_package_variable(&var).unwrap();
+                        map.insert(
+                            Package::Base(package.to_string()),
+                            self.index.package_variable_range(package, var, &flip_relop(relop), &ver),
+                        )
+                    }
+                    (VersionFormula::Variable(var), VersionFormula::Lit(ver))
+                        if split_package_variable(&var).is_some() =>
+                    {
+                        let (package, var) = split_package_variable(&var).unwrap();
+                        map.insert(
+                            Package::Base(package.to_string()),
+                            self.index.package_variable_range(package, var, relop, &ver),
+                        )
+                    }
+                    (VersionFormula::Lit(ver), VersionFormula::Variable(var)) => {
+                        self.variables
+                            .borrow_mut()
+                            .entry(var.to_string())
+                            .or_default()
+                            .insert(ver.clone());
+                        let range = relop_to_range(&flip_relop(relop), ver).intersection(&defined_range());
+                        map.insert(Package::Var(var.to_string()), range)
+                    }
+                    (VersionFormula::Variable(var), VersionFormula::Lit(ver)) => {
+                        self.variables
+                            .borrow_mut()
+                            .entry(var.to_string())
+                            .or_default()
+                            .insert(ver.clone());
+                        let range = relop_to_range(relop, ver).intersection(&defined_range());
+                        map.insert(Package::Var(var.to_string()), range)
+                    }
+                    // a proxy would pick the value of one package variable, or of a filter,
+                    // and we can't list the values of a package variable
+                    (lhs, rhs)
+                        if operand_rank(&lhs).min(operand_rank(&rhs)) > 0
+                            && operand_rank(&lhs).max(operand_rank(&rhs)) == 2 =>
+                    {
+                        return Err(ErrorKind::UnsupportedComparison(formula.to_string()))
+                    }
+                    // otherwise a proxy picks the value of the left operand, so we put the
+                    // operand with the fewest values there
+                    (lhs, rhs) => {
+                        let formula = if operand_rank(&lhs) > operand_rank(&rhs) {
+                            VersionFormula::Comparator {
+                                relop: flip_relop(relop),
+                                binary: Binary {
+                                    lhs: Box::new(rhs),
+                                    rhs: Box::new(lhs),
+                                },
+                            }
+                        } else {
+                            formula.clone()
+                        };
+                        map.insert(
+                            Package::Proxy {
+                                name: name.cloned(),
+                                formula: Box::new(formula),
+                            },
+                            Range::full(),
+                        )
+                    }
+                };
+                map
+            }
+            VersionFormula::Lit(lit) if *lit == *TRUE_VERSION => map,
+            // a literal other than a boolean, e.g. `{2}`, isn't a filter, as in opam
+            VersionFormula::Lit(_) => return Err(ErrorKind::InvalidLiteral(formula.to_string())),
+            // left when the value isn't known, e.g. `>= os`
+            VersionFormula::Constraint { .. } => {
+                return Err(ErrorKind::UnexpectedFormula(formula.to_string()))
+            }
+        })
     }
 }
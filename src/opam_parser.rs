@@ -0,0 +1,538 @@
+use serde_json::{Map, Value};
+
+/// Parses the contents of an `opam` file into the same JSON shape `opam2json`
+/// produces, so the result can be deserialized into `parse::OpamJson` just like
+/// an `opam.json` file.
+///
+/// The mapping follows `opam2json`:
+///   "str"              -> "str"
+///   ident              -> { "id": "ident" }
+///   [ a b ]            -> [ a, b ]
+///   ( a b )            -> { "group": [ a, b ] }
+///   a { b c }          -> { "val": a, "conditions": [ b, c ] }
+///   a & b, a | b       -> { "logop": "and" | "or", "lhs": a, "rhs": b }
+///   !a, ?a             -> { "pfxop": "not" | "defined", "arg": a }
+///   a >= b             -> { "relop": "geq", "lhs": a, "rhs": b }
+///   >= a               -> { "prefix_relop": "geq", "arg": a }
+pub fn parse_opam(content: &str) -> Result<Value, String> {
+    let tokens = tokenize(content)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let items = parser.items(false)?;
+    Ok(Value::Object(items))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Field(String),
+    Ident(String),
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    Relop(&'static str),
+    Envop(&'static str),
+    And,
+    Or,
+    Not,
+    Defined,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+}
+
+struct Lexed {
+    token: Token,
+    line: usize,
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+' || c == ':'
+}
+
+fn tokenize(content: &str) -> Result<Vec<Lexed>, String> {
+    let chars: Vec<char> = content.chars().collect();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            '\n' => {
+                line += 1;
+                i += 1;
+            }
+            c if c.is_whitespace() => i += 1,
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '(' if next == Some('*') => {
+                // comments nest in opam files
+                let mut depth = 0;
+                loop {
+                    match (chars.get(i), chars.get(i + 1)) {
+                        (Some('('), Some('*')) => {
+                            depth += 1;
+                            i += 2;
+                        }
+                        (Some('*'), Some(')')) => {
+                            depth -= 1;
+                            i += 2;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        (Some('\n'), _) => {
+                            line += 1;
+                            i += 1;
+                        }
+                        (Some(_), _) => i += 1,
+                        (None, _) => return Err(format!("line {}: unterminated comment", line)),
+                    }
+                }
+            }
+            '"' => {
+                let start_line = line;
+                let triple = next == Some('"') && chars.get(i + 2) == Some(&'"');
+                i += if triple { 3 } else { 1 };
+                // opam strings are bytes, which escapes give one at a time, so they're
+                // collected and read as UTF-8 once the string ends
+                let mut s: Vec<u8> = Vec::new();
+                loop {
+                    match chars.get(i) {
+                        None => return Err(format!("line {}: unterminated string", start_line)),
+                        Some('"') if !triple => {
+                            i += 1;
+                            break;
+                        }
+                        Some('"')
+                            if triple
+                                && chars.get(i + 1) == Some(&'"')
+                                && chars.get(i + 2) == Some(&'"') =>
+                        {
+                            i += 3;
+                            break;
+                        }
+                        Some('\\') => {
+                            i += 1;
+                            match chars.get(i) {
+                                Some('n') => s.push(b'\n'),
+                                Some('t') => s.push(b'\t'),
+                                Some('r') => s.push(b'\r'),
+                                Some('b') => s.push(8),
+                                Some(' ') => s.push(b' '),
+                                Some('\n') => {
+                                    // an escaped newline skips the leading blanks of the next line
+                                    line += 1;
+                                    while matches!(chars.get(i + 1), Some(' ') | Some('\t')) {
+                                        i += 1;
+                                    }
+                                }
+                                Some(d) if d.is_ascii_digit() => {
+                                    let code: String = chars[i..(i + 3).min(chars.len())].iter().collect();
+                                    let code = code
+                                        .parse::<u8>()
+                                        .map_err(|_| format!("line {}: invalid escape \\{}", line, code))?;
+                                    s.push(code);
+                                    i += 2;
+                                }
+                                Some('x') => {
+                                    let code: String = chars[(i + 1)..(i + 3).min(chars.len())].iter().collect();
+                                    let code = u8::from_str_radix(&code, 16)
+                                        .map_err(|_| format!("line {}: invalid escape \\x{}", line, code))?;
+                                    s.push(code);
+                                    i += 2;
+                                }
+                                Some(c) => s.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                                None => return Err(format!("line {}: unterminated string", start_line)),
+                            }
+                            i += 1;
+                        }
+                        Some(c) => {
+                            if *c == '\n' {
+                                line += 1;
+                            }
+                            s.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                            i += 1;
+                        }
+                    }
+                }
+                let s = String::from_utf8_lossy(&s).into_owned();
+                tokens.push(Lexed { token: Token::Str(s), line: start_line });
+            }
+            '[' | ']' | '(' | ')' | '{' | '}' | '&' | '|' | '?' => {
+                let token = match c {
+                    '[' => Token::LBracket,
+                    ']' => Token::RBracket,
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '{' => Token::LBrace,
+                    '}' => Token::RBrace,
+                    '&' => Token::And,
+                    '|' => Token::Or,
+                    _ => Token::Defined,
+                };
+                tokens.push(Lexed { token, line });
+                i += 1;
+            }
+            '!' | '=' | '<' | '>' | '+' | ':' => {
+                let rest: String = chars[i..(i + 3).min(chars.len())].iter().collect();
+                let (token, len) = if rest.starts_with("=+=") {
+                    (Token::Envop("=+="), 3)
+                } else if rest.starts_with("!=") {
+                    (Token::Relop("neq"), 2)
+                } else if rest.starts_with("<=") {
+                    (Token::Relop("leq"), 2)
+                } else if rest.starts_with(">=") {
+                    (Token::Relop("geq"), 2)
+                } else if rest.starts_with("+=") {
+                    (Token::Envop("+="), 2)
+                } else if rest.starts_with("=+") {
+                    (Token::Envop("=+"), 2)
+                } else if rest.starts_with(":=") {
+                    (Token::Envop(":="), 2)
+                } else if rest.starts_with("=:") {
+                    (Token::Envop("=:"), 2)
+                } else if c == '!' {
+                    (Token::Not, 1)
+                } else if c == '=' {
+                    (Token::Relop("eq"), 1)
+                } else if c == '<' {
+                    (Token::Relop("lt"), 1)
+                } else if c == '>' {
+                    (Token::Relop("gt"), 1)
+                } else {
+                    return Err(format!("line {}: unexpected character '{}'", line, c));
+                };
+                tokens.push(Lexed { token, line });
+                i += len;
+            }
+            c if c.is_ascii_digit() || (c == '-' && next.is_some_and(|n| n.is_ascii_digit())) => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                if i < chars.len() && is_ident_char(chars[i]) && chars[i] != ':' {
+                    return Err(format!("line {}: invalid integer", line));
+                }
+                let n: String = chars[start..i].iter().collect();
+                let n = n.parse::<i64>().map_err(|e| format!("line {}: {}", line, e))?;
+                tokens.push(Lexed { token: Token::Int(n), line });
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && is_ident_char(chars[i]) {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                let token = if let Some(field) = word.strip_suffix(':') {
+                    Token::Field(field.to_string())
+                } else {
+                    match word.as_str() {
+                        "true" => Token::Bool(true),
+                        "false" => Token::Bool(false),
+                        _ => Token::Ident(word),
+                    }
+                };
+                tokens.push(Lexed { token, line });
+            }
+            c => return Err(format!("line {}: unexpected character '{}'", line, c)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Lexed>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|t| &t.token)
+    }
+
+    fn line(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some(t) => t.line,
+            None => self.tokens.last().map(|t| t.line).unwrap_or(1),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|t| t.token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        let line = self.line();
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("line {}: expected {:?}, found {:?}", line, expected, token)),
+            None => Err(format!("line {}: expected {:?}, found end of file", line, expected)),
+        }
+    }
+
+    // file := item*
+    // item := field value | ident string? '{' item* '}'
+    fn items(&mut self, in_section: bool) -> Result<Map<String, Value>, String> {
+        let mut items = Map::new();
+        loop {
+            let line = self.line();
+            match self.next() {
+                None if !in_section => return Ok(items),
+                Some(Token::RBrace) if in_section => return Ok(items),
+                Some(Token::Field(name)) => {
+                    let value = self.value()?;
+                    items.insert(name, value);
+                }
+                Some(Token::Ident(kind)) => {
+                    let name = match self.peek() {
+                        Some(Token::Str(name)) => {
+                            let name = name.clone();
+                            self.pos += 1;
+                            Some(name)
+                        }
+                        _ => None,
+                    };
+                    self.expect(Token::LBrace)?;
+                    let section = Value::Object(self.items(true)?);
+                    match name {
+                        Some(name) => {
+                            let entry = items
+                                .entry(kind)
+                                .or_insert_with(|| Value::Object(Map::new()));
+                            if let Value::Object(sections) = entry {
+                                sections.insert(name, section);
+                            }
+                        }
+                        None => {
+                            items.insert(kind, section);
+                        }
+                    }
+                }
+                None => return Err(format!("line {}: unterminated section", line)),
+                Some(token) => return Err(format!("line {}: unexpected {:?}", line, token)),
+            }
+        }
+    }
+
+    // values := value* (up to a closing delimiter)
+    fn values(&mut self, close: Token) -> Result<Vec<Value>, String> {
+        let mut values = Vec::new();
+        loop {
+            match self.peek() {
+                Some(token) if *token == close => {
+                    self.pos += 1;
+                    return Ok(values);
+                }
+                None => {
+                    return Err(format!("line {}: expected {:?}, found end of file", self.line(), close))
+                }
+                _ => values.push(self.value()?),
+            }
+        }
+    }
+
+    // value := and ('|' and)*
+    fn value(&mut self) -> Result<Value, String> {
+        let mut lhs = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            let rhs = self.and()?;
+            lhs = logop("or", lhs, rhs);
+        }
+        Ok(lhs)
+    }
+
+    // and := unary ('&' unary)*
+    fn and(&mut self) -> Result<Value, String> {
+        let mut lhs = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            let rhs = self.unary()?;
+            lhs = logop("and", lhs, rhs);
+        }
+        Ok(lhs)
+    }
+
+    // unary := ('!' | '?') unary | relop option | relation
+    fn unary(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some(Token::Not) | Some(Token::Defined) => {
+                let pfxop = if self.next() == Some(Token::Not) { "not" } else { "defined" };
+                let arg = self.unary()?;
+                let mut map = Map::new();
+                map.insert("pfxop".to_string(), Value::String(pfxop.to_string()));
+                map.insert("arg".to_string(), arg);
+                Ok(Value::Object(map))
+            }
+            Some(Token::Relop(relop)) => {
+                let relop = relop.to_string();
+                self.pos += 1;
+                let arg = self.option()?;
+                let mut map = Map::new();
+                map.insert("prefix_relop".to_string(), Value::String(relop));
+                map.insert("arg".to_string(), arg);
+                Ok(Value::Object(map))
+            }
+            _ => self.relation(),
+        }
+    }
+
+    // relation := option ((relop | envop) option)?
+    fn relation(&mut self) -> Result<Value, String> {
+        let lhs = self.option()?;
+        match self.peek() {
+            Some(Token::Relop(relop)) => {
+                let relop = relop.to_string();
+                self.pos += 1;
+                let rhs = self.option()?;
+                let mut map = Map::new();
+                map.insert("relop".to_string(), Value::String(relop));
+                map.insert("lhs".to_string(), lhs);
+                map.insert("rhs".to_string(), rhs);
+                Ok(Value::Object(map))
+            }
+            Some(Token::Envop(envop)) => {
+                let envop = envop.to_string();
+                self.pos += 1;
+                let rhs = self.option()?;
+                let mut map = Map::new();
+                map.insert("envop".to_string(), Value::String(envop));
+                map.insert("lhs".to_string(), lhs);
+                map.insert("rhs".to_string(), rhs);
+                Ok(Value::Object(map))
+            }
+            _ => Ok(lhs),
+        }
+    }
+
+    // option := atom ('{' values '}')*
+    fn option(&mut self) -> Result<Value, String> {
+        let mut value = self.atom()?;
+        while self.peek() == Some(&Token::LBrace) {
+            self.pos += 1;
+            let conditions = self.values(Token::RBrace)?;
+            let mut map = Map::new();
+            map.insert("val".to_string(), value);
+            map.insert("conditions".to_string(), Value::Array(conditions));
+            value = Value::Object(map);
+        }
+        Ok(value)
+    }
+
+    // atom := string | int | bool | ident | '[' values ']' | '(' values ')'
+    fn atom(&mut self) -> Result<Value, String> {
+        let line = self.line();
+        match self.next() {
+            Some(Token::Str(s)) => Ok(Value::String(s)),
+            Some(Token::Int(n)) => Ok(Value::from(n)),
+            Some(Token::Bool(b)) => Ok(Value::Bool(b)),
+            Some(Token::Ident(id)) => {
+                let mut map = Map::new();
+                map.insert("id".to_string(), Value::String(id));
+                Ok(Value::Object(map))
+            }
+            Some(Token::LBracket) => Ok(Value::Array(self.values(Token::RBracket)?)),
+            Some(Token::LParen) => {
+                let group = self.values(Token::RParen)?;
+                let mut map = Map::new();
+                map.insert("group".to_string(), Value::Array(group));
+                Ok(Value::Object(map))
+            }
+            Some(token) => Err(format!("line {}: unexpected {:?}", line, token)),
+            None => Err(format!("line {}: unexpected end of file", line)),
+        }
+    }
+}
+
+fn logop(op: &str, lhs: Value, rhs: Value) -> Value {
+    let mut map = Map::new();
+    map.insert("logop".to_string(), Value::String(op.to_string()));
+    map.insert("lhs".to_string(), lhs);
+    map.insert("rhs".to_string(), rhs);
+    Value::Object(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::OpamJson;
+    use std::fs;
+    use walkdir::WalkDir;
+
+    #[test]
+    fn test_matches_opam2json() {
+        // every fixture in the example repository is checked in both as an opam
+        // file and as its opam2json rendering, so the two must agree
+        for entry in WalkDir::new("./example-repo/packages") {
+            let entry = entry.unwrap();
+            if entry.file_name() != "opam" {
+                continue;
+            }
+            let json_file = entry.path().with_extension("json");
+            if !json_file.exists() {
+                continue;
+            }
+            let opam = fs::read_to_string(entry.path()).unwrap();
+            let json = fs::read_to_string(json_file).unwrap();
+            let native: OpamJson = serde_json::from_value(parse_opam(&opam).unwrap()).unwrap();
+            let expected: OpamJson = serde_json::from_str(&json).unwrap();
+            assert_eq!(native, expected, "{}", entry.path().display());
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        let value = parse_opam(r#"depends: [ "A" { !test & os = "linux" | >= "1.0" } ]"#).unwrap();
+        let expected = serde_json::json!({
+            "depends": [ {
+                "val": "A",
+                "conditions": [ {
+                    "logop": "or",
+                    "lhs": {
+                        "logop": "and",
+                        "lhs": { "pfxop": "not", "arg": { "id": "test" } },
+                        "rhs": { "relop": "eq", "lhs": { "id": "os" }, "rhs": "linux" }
+                    },
+                    "rhs": { "prefix_relop": "geq", "arg": "1.0" }
+                } ]
+            } ]
+        });
+        assert_eq!(value, expected);
+    }
+
+    #[test]
+    fn test_sections_and_comments() {
+        let value = parse_opam(
+            r#"
+            opam-version: "2.0" # trailing comment
+            (* a (* nested *) comment *)
+            build: [["dune" "build" "-p" name "-j" jobs] {with-test}]
+            url { src: "https://example.com/a.tar.gz" }
+            extra-source "a.patch" { src: """p"a"tch""" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(value["opam-version"], "2.0");
+        assert_eq!(value["url"]["src"], "https://example.com/a.tar.gz");
+        assert_eq!(value["extra-source"]["a.patch"]["src"], "p\"a\"tch");
+        assert_eq!(value["build"][0]["conditions"][0]["id"], "with-test");
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        // escapes are bytes, so a character of more than one byte takes several
+        let value = parse_opam(r#"a: "caf\195\169" b: "caf\xc3\xa9" c: "café\t""#).unwrap();
+        assert_eq!(value["a"], "café");
+        assert_eq!(value["b"], "café");
+        assert_eq!(value["c"], "café\t");
+    }
+}
@@ -36,7 +36,7 @@ fn tokenize(version: &str) -> Vec<Token> {
         }
     }
 
-    while let Some(ch) = chars.next() {
+    for ch in chars {
         let ch_is_digit = ch.is_ascii_digit();
         match is_digit {
             Some(current_is_digit) if current_is_digit == ch_is_digit => {
@@ -1,30 +1,63 @@
+use crate::error::{Error, ErrorKind};
 use crate::index::{Binary, HashedRange, PackageFormula, VersionFormula};
+use crate::opam_deps::{FALSE_VERSION, TRUE_VERSION};
+use crate::opam_parser::parse_opam;
 use crate::opam_version::OpamVersion;
 use pubgrub::Range;
 use serde::Deserialize;
-use std::error::Error;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize)]
 pub struct OpamJson {
     #[serde(rename = "opam-version")]
     pub opam_version: Option<String>,
     pub name: Option<String>,
     pub version: Option<String>,
     pub depends: Option<DependsField>,
+    pub depopts: Option<DependsField>,
+    pub conflicts: Option<DependsField>,
+    pub available: Option<AvailableField>,
     #[serde(rename = "conflict-class")]
-    pub conflict_class: Option<String>,
+    pub conflict_class: Option<ConflictClassField>,
+    pub flags: Option<FlagsField>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize)]
 #[serde(untagged)]
 pub enum DependsField {
     Single(OpamPackageFormula),
     Multiple(Vec<OpamPackageFormula>),
 }
 
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum ConflictClassField {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+/// A flag such as `avoid-version`, which opam writes as an identifier.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct Flag {
+    pub id: String,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum FlagsField {
+    Single(Flag),
+    Multiple(Vec<Flag>),
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum AvailableField {
+    Single(FilterExpr),
+    Multiple(Vec<FilterExpr>),
+}
+
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum LogicalOp {
@@ -102,9 +135,8 @@ pub enum FilterOrVersion {
 #[serde(untagged)]
 pub enum LiteralValue {
     Str(String),
-    // TODO implement logic for these if they're actually used anywhere
-    // Int(i64),
-    // Bool(bool),
+    Int(i64),
+    Bool(bool),
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
@@ -144,6 +176,18 @@ pub fn negate_relop(relop: RelOp) -> RelOp {
     }
 }
 
+/// The relation with its operands swapped, i.e. `a op b` iff `b (flip op) a`.
+pub fn flip_relop(relop: &RelOp) -> RelOp {
+    match relop {
+        RelOp::Eq => RelOp::Eq,
+        RelOp::Neq => RelOp::Neq,
+        RelOp::Geq => RelOp::Leq,
+        RelOp::Gt => RelOp::Lt,
+        RelOp::Leq => RelOp::Geq,
+        RelOp::Lt => RelOp::Gt,
+    }
+}
+
 pub fn relop_to_range(relop: &RelOp, version: OpamVersion) -> Range<OpamVersion> {
     match relop {
         RelOp::Eq => Range::<OpamVersion>::singleton(version),
@@ -155,8 +199,19 @@ pub fn relop_to_range(relop: &RelOp, version: OpamVersion) -> Range<OpamVersion>
     }
 }
 
+/// The negation of a boolean literal; other literals are left as they are.
+pub fn negate_literal(lit: OpamVersion) -> OpamVersion {
+    if lit == *TRUE_VERSION {
+        FALSE_VERSION.clone()
+    } else if lit == *FALSE_VERSION {
+        TRUE_VERSION.clone()
+    } else {
+        lit
+    }
+}
+
 // not quite CNF, we just move negations to the leaves
-fn normalize_negation(expr: VersionFormula) -> VersionFormula {
+pub fn normalize_negation(expr: VersionFormula) -> VersionFormula {
     match expr {
         VersionFormula::Version(version) => {
             VersionFormula::Version(HashedRange(version.0.complement()))
@@ -176,182 +231,249 @@ fn normalize_negation(expr: VersionFormula) -> VersionFormula {
             relop: negate_relop(relop),
             binary,
         },
-        _ => expr,
+        VersionFormula::Constraint { relop, arg } => VersionFormula::Constraint {
+            relop: negate_relop(relop),
+            arg,
+        },
+        VersionFormula::Lit(lit) => VersionFormula::Lit(negate_literal(lit)),
+        VersionFormula::Defined(variable) => VersionFormula::Undefined(variable),
+        VersionFormula::Undefined(variable) => VersionFormula::Defined(variable),
+    }
+}
+
+/// Whether a formula has a value, i.e. `?formula`. Literals and version constraints are
+/// always defined, and a comparison is defined when both of its operands are. As in
+/// opam, `a | b` is also defined when either side is true, and `a & b` when either side
+/// is false, since that side decides the value on its own.
+fn defined_formula(formula: VersionFormula) -> VersionFormula {
+    match formula {
+        VersionFormula::Variable(var) | VersionFormula::Not(var) => VersionFormula::Defined(var),
+        VersionFormula::Or(Binary { lhs, rhs }) => or_version_formula(
+            and_version_formula(defined_formula(*lhs.clone()), defined_formula(*rhs.clone())),
+            or_version_formula(*lhs, *rhs),
+        ),
+        VersionFormula::And(Binary { lhs, rhs }) => or_version_formula(
+            and_version_formula(defined_formula(*lhs.clone()), defined_formula(*rhs.clone())),
+            or_version_formula(normalize_negation(*lhs), normalize_negation(*rhs)),
+        ),
+        VersionFormula::Comparator {
+            relop: _,
+            binary: Binary { lhs, rhs },
+        } => and_version_formula(defined_formula(*lhs), defined_formula(*rhs)),
+        VersionFormula::Constraint { relop: _, arg } => defined_formula(*arg),
+        VersionFormula::Lit(_)
+        | VersionFormula::Version(_)
+        | VersionFormula::Defined(_)
+        | VersionFormula::Undefined(_) => VersionFormula::Lit(TRUE_VERSION.clone()),
+    }
+}
+
+pub fn and_version_formula(left: VersionFormula, right: VersionFormula) -> VersionFormula {
+    match (left, right) {
+        (VersionFormula::Version(l), VersionFormula::Version(r)) => {
+            VersionFormula::Version(HashedRange(l.0.intersection(&r.0)))
+        }
+        (left, right) => VersionFormula::And(Binary {
+            lhs: Box::new(left),
+            rhs: Box::new(right),
+        }),
     }
 }
 
-fn parse_filter_expr(filter: &FilterExpr) -> VersionFormula {
+pub fn or_version_formula(left: VersionFormula, right: VersionFormula) -> VersionFormula {
+    match (left, right) {
+        (VersionFormula::Version(l), VersionFormula::Version(r)) => {
+            VersionFormula::Version(HashedRange(l.0.union(&r.0)))
+        }
+        (left, right) => VersionFormula::Or(Binary {
+            lhs: Box::new(left),
+            rhs: Box::new(right),
+        }),
+    }
+}
+
+/// Conjoins a list of formulas, as found in groups and `conditions`, where an empty
+/// list is `empty`.
+fn conjoin_version_formulas(
+    formulas: impl Iterator<Item = Result<VersionFormula, ErrorKind>>,
+    empty: VersionFormula,
+) -> Result<VersionFormula, ErrorKind> {
+    let formulas = formulas.collect::<Result<Vec<_>, _>>()?;
+    Ok(formulas.into_iter().reduce(and_version_formula).unwrap_or(empty))
+}
+
+/// An empty group, which is `true` wherever it is, so that `!()` is `false` rather than
+/// no version at all.
+fn empty_group() -> VersionFormula {
+    VersionFormula::Lit(TRUE_VERSION.clone())
+}
+
+fn parse_filter_expr(filter: &FilterExpr) -> Result<VersionFormula, ErrorKind> {
     match filter {
         FilterExpr::LogOp { logop, lhs, rhs } => {
-            let left = parse_filter_expr(lhs);
-            let right = parse_filter_expr(rhs);
-            match logop {
-                LogicalOp::And => match (left.clone(), right.clone()) {
-                    (VersionFormula::Version(l), VersionFormula::Version(r)) => {
-                        VersionFormula::Version(HashedRange(l.0.intersection(&r.0)))
-                    }
-                    _ => VersionFormula::And(Binary {
-                        lhs: Box::new(left),
-                        rhs: Box::new(right),
-                    }),
-                },
-                LogicalOp::Or => match (left.clone(), right.clone()) {
-                    (VersionFormula::Version(l), VersionFormula::Version(r)) => {
-                        VersionFormula::Version(HashedRange(l.0.union(&r.0)))
-                    }
-                    _ => VersionFormula::Or(Binary {
-                        lhs: Box::new(left),
-                        rhs: Box::new(right),
-                    }),
-                },
-            }
+            let left = parse_filter_expr(lhs)?;
+            let right = parse_filter_expr(rhs)?;
+            Ok(match logop {
+                LogicalOp::And => and_version_formula(left, right),
+                LogicalOp::Or => or_version_formula(left, right),
+            })
         }
         FilterExpr::Unary { pfxop, arg } => match pfxop {
             UnaryOp::Not => {
-                let inner = parse_filter_expr(*&arg);
-                normalize_negation(inner)
+                let inner = parse_filter_expr(arg)?;
+                Ok(normalize_negation(inner))
             }
-            UnaryOp::Defined => match parse_filter_expr(*&arg) {
-                VersionFormula::Variable(id) => VersionFormula::Comparator {
-                    relop: RelOp::Neq,
-                    binary: Binary {
-                        lhs: Box::new(VersionFormula::Variable(id)),
-                        rhs: Box::new(VersionFormula::Version(HashedRange(Range::singleton(
-                            OpamVersion("".to_string()),
-                        )))),
-                    },
-                },
-                _ => panic!("Defined must be for a variable"),
-            },
+            UnaryOp::Defined => Ok(defined_formula(parse_filter_expr(arg)?)),
         },
         FilterExpr::Group { group } => {
-            if group.is_empty() {
-                panic!("Empty group");
-            } else {
-                parse_filter_expr(&group[0])
-            }
+            conjoin_version_formulas(group.iter().map(parse_filter_expr), empty_group())
         }
         FilterExpr::Relop { relop, lhs, rhs } => {
-            let left = parse_filter_expr(lhs);
-            let right = parse_filter_expr(rhs);
-            VersionFormula::Comparator {
+            let left = parse_filter_expr(lhs)?;
+            let right = parse_filter_expr(rhs)?;
+            Ok(VersionFormula::Comparator {
                 relop: relop.clone(),
                 binary: Binary {
                     lhs: Box::new(left),
                     rhs: Box::new(right),
                 },
-            }
+            })
         }
-        FilterExpr::Variable { id } => VersionFormula::Variable(id.to_string()),
+        FilterExpr::Variable { id } => Ok(VersionFormula::Variable(id.to_string())),
         FilterExpr::Literal(lit) => match lit {
             LiteralValue::Str(s) => {
-                let version = s.parse::<OpamVersion>().unwrap();
-                VersionFormula::Lit(version)
+                let version = s.parse::<OpamVersion>().map_err(ErrorKind::Syntax)?;
+                Ok(VersionFormula::Lit(version))
             }
+            // integers compare as versions do, as in opam, which orders the digits of a
+            // version numerically, so `jobs >= 2` holds for `jobs = 10`; booleans are the
+            // versions of variables, and any other literal on its own isn't a filter
+            LiteralValue::Int(n) => Ok(VersionFormula::Lit(OpamVersion(n.to_string()))),
+            LiteralValue::Bool(true) => Ok(VersionFormula::Lit(TRUE_VERSION.clone())),
+            LiteralValue::Bool(false) => Ok(VersionFormula::Lit(FALSE_VERSION.clone())),
         },
     }
 }
 
-fn parse_version_formula(formula: &OpamVersionFormula) -> VersionFormula {
+fn parse_version_formula(formula: &OpamVersionFormula) -> Result<VersionFormula, ErrorKind> {
     match formula {
         OpamVersionFormula::LogOp { logop, lhs, rhs } => {
-            let left = parse_version_formula(lhs);
-            let right = parse_version_formula(rhs);
-            match logop {
-                LogicalOp::And => match (left.clone(), right.clone()) {
-                    (VersionFormula::Version(l), VersionFormula::Version(r)) => {
-                        VersionFormula::Version(HashedRange(l.0.intersection(&r.0)))
-                    }
-                    _ => VersionFormula::And(Binary {
-                        lhs: Box::new(left),
-                        rhs: Box::new(right),
-                    }),
-                },
-                LogicalOp::Or => match (left.clone(), right.clone()) {
-                    (VersionFormula::Version(l), VersionFormula::Version(r)) => {
-                        VersionFormula::Version(HashedRange(l.0.union(&r.0)))
-                    }
-                    _ => VersionFormula::Or(Binary {
-                        lhs: Box::new(left),
-                        rhs: Box::new(right),
-                    }),
-                },
-            }
+            let left = parse_version_formula(lhs)?;
+            let right = parse_version_formula(rhs)?;
+            Ok(match logop {
+                LogicalOp::And => and_version_formula(left, right),
+                LogicalOp::Or => or_version_formula(left, right),
+            })
         }
         OpamVersionFormula::PrefixRelop { prefix_relop, arg } => match arg {
             FilterOrVersion::Version(version) => {
-                let version = version.parse::<OpamVersion>().unwrap();
+                let version = version.parse::<OpamVersion>().map_err(ErrorKind::Syntax)?;
                 let range = relop_to_range(prefix_relop, version);
-                VersionFormula::Version(HashedRange(range))
+                Ok(VersionFormula::Version(HashedRange(range)))
             }
-            FilterOrVersion::Filter(filter) => parse_filter_expr(filter),
+            // e.g. `= version`, resolved once we know the depending package
+            FilterOrVersion::Filter(filter) => Ok(VersionFormula::Constraint {
+                relop: prefix_relop.clone(),
+                arg: Box::new(parse_filter_expr(filter)?),
+            }),
         },
         OpamVersionFormula::Group { group } => {
-            if group.is_empty() {
-                panic!("Empty group");
-            } else {
-                parse_version_formula(&group[0])
-            }
+            conjoin_version_formulas(group.iter().map(parse_version_formula), empty_group())
         }
         OpamVersionFormula::PrefixOperator { pfxop, arg } => match pfxop {
             UnaryOp::Not => {
-                let inner = parse_version_formula(*&arg);
-                normalize_negation(inner)
+                let inner = parse_version_formula(arg)?;
+                Ok(normalize_negation(inner))
             }
-            UnaryOp::Defined => match parse_version_formula(*&arg) {
-                VersionFormula::Variable(id) => VersionFormula::Comparator {
-                    relop: RelOp::Neq,
-                    binary: Binary {
-                        lhs: Box::new(VersionFormula::Variable(id)),
-                        rhs: Box::new(VersionFormula::Version(HashedRange(Range::singleton(
-                            OpamVersion("".to_string()),
-                        )))),
-                    },
-                },
-                _ => panic!("Defined must be for a variable"),
-            },
+            UnaryOp::Defined => Ok(defined_formula(parse_version_formula(arg)?)),
         },
         OpamVersionFormula::Filter(filter) => parse_filter_expr(filter),
     }
 }
 
-pub fn parse_package_formula(formula: &OpamPackageFormula) -> PackageFormula {
-    match formula {
-        OpamPackageFormula::Simple { name, conditions } => {
-            let formula = if conditions.is_empty() {
-                VersionFormula::Version(HashedRange(Range::full()))
-            } else {
-                parse_version_formula(&conditions[0])
-            };
-            PackageFormula::Base {
-                name: name.clone(),
-                formula,
-            }
-        }
+/// Converts an opam package formula, returning `None` if it is trivially `true`
+/// (e.g. an empty group).
+pub fn parse_package_formula(
+    formula: &OpamPackageFormula,
+) -> Result<Option<PackageFormula>, ErrorKind> {
+    Ok(match formula {
+        OpamPackageFormula::Simple { name, conditions } => Some(PackageFormula::Base {
+            name: name.clone(),
+            // no conditions at all allow any version
+            formula: conjoin_version_formulas(
+                conditions.iter().map(parse_version_formula),
+                VersionFormula::Version(HashedRange(Range::full())),
+            )?,
+        }),
         // For a binary formula, recursively convert the left- and right-hand sides.
         OpamPackageFormula::Binary { logop, lhs, rhs } => {
-            let lhs_conv = parse_package_formula(lhs);
-            let rhs_conv = parse_package_formula(rhs);
-            let binary = Binary {
-                lhs: Box::new(lhs_conv),
-                rhs: Box::new(rhs_conv),
-            };
-            match logop {
-                LogicalOp::And => PackageFormula::And(binary),
-                LogicalOp::Or => PackageFormula::Or(binary),
-            }
-        }
-        OpamPackageFormula::Group { group } => {
-            if group.is_empty() {
-                panic!("Empty group");
-            } else {
-                parse_package_formula(&group[0])
+            match (parse_package_formula(lhs)?, parse_package_formula(rhs)?, logop) {
+                (Some(lhs), Some(rhs), _) => {
+                    let binary = Binary {
+                        lhs: Box::new(lhs),
+                        rhs: Box::new(rhs),
+                    };
+                    match logop {
+                        LogicalOp::And => Some(PackageFormula::And(binary)),
+                        LogicalOp::Or => Some(PackageFormula::Or(binary)),
+                    }
+                }
+                // `true & f` is `f`
+                (Some(f), None, LogicalOp::And) | (None, Some(f), LogicalOp::And) => Some(f),
+                // `true | f` is `true`
+                _ => None,
             }
         }
-        OpamPackageFormula::Plain(s) => PackageFormula::Base {
+        OpamPackageFormula::Group { group } => group
+            .iter()
+            .map(parse_package_formula)
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .reduce(|lhs, rhs| {
+                PackageFormula::And(Binary {
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                })
+            }),
+        OpamPackageFormula::Plain(s) => Some(PackageFormula::Base {
             name: s.clone(),
             formula: VersionFormula::Version(HashedRange(Range::full())),
-        },
+        }),
+    })
+}
+
+/// Turns a `conflicts` formula into the formula over `PackageFormula::Conflict`s
+/// that must hold: conflicting with `a & b` means avoiding either, and
+/// conflicting with `a | b` means avoiding both.
+fn conflicts_of(formula: PackageFormula) -> PackageFormula {
+    match formula {
+        PackageFormula::Base { name, formula } => PackageFormula::Conflict { name, formula },
+        PackageFormula::And(Binary { lhs, rhs }) => PackageFormula::Or(Binary {
+            lhs: Box::new(conflicts_of(*lhs)),
+            rhs: Box::new(conflicts_of(*rhs)),
+        }),
+        PackageFormula::Or(Binary { lhs, rhs }) => PackageFormula::And(Binary {
+            lhs: Box::new(conflicts_of(*lhs)),
+            rhs: Box::new(conflicts_of(*rhs)),
+        }),
+        _ => formula,
+    }
+}
+
+/// Turns a `depopts` formula into `PackageFormula::Depopt`s. Each optional dependency
+/// is constrained independently, so disjunctions (which opam deprecates in `depopts`)
+/// are treated like conjunctions.
+fn depopts_of(formula: PackageFormula) -> PackageFormula {
+    match formula {
+        PackageFormula::Base { name, formula } => PackageFormula::Depopt { name, formula },
+        PackageFormula::And(Binary { lhs, rhs }) | PackageFormula::Or(Binary { lhs, rhs }) => {
+            PackageFormula::And(Binary {
+                lhs: Box::new(depopts_of(*lhs)),
+                rhs: Box::new(depopts_of(*rhs)),
+            })
+        }
+        _ => formula,
     }
 }
 
@@ -359,22 +481,23 @@ pub fn parse_package_formula(formula: &OpamPackageFormula) -> PackageFormula {
 /// for that package, in descending order (newest first).
 ///
 /// The repository is assumed to have the following structure:
-///   repo_path/package-name/package-name.version/opam.json
+///   repo_path/package-name/package-name.version/opam
 pub fn available_versions_from_repo(
     repo_path: &str,
     package: &str,
-) -> Result<Vec<OpamVersion>, Box<dyn Error>> {
+) -> Result<Vec<OpamVersion>, Error> {
     // Construct the package directory: repo_path/package
     let pkg_dir = Path::new(repo_path).join(package);
+    let io_error = |e: std::io::Error| Error::from(ErrorKind::Io(e.to_string())).with_path(&pkg_dir);
     if !pkg_dir.exists() {
-        return Err(format!("Package path {} does not exist", pkg_dir.display()).into());
+        return Err(Error::from(ErrorKind::MissingPackage).with_path(&pkg_dir));
     }
 
     let mut versions = Vec::new();
     // Read the package directory: each subdirectory is assumed to be a version folder.
-    for entry in fs::read_dir(&pkg_dir)? {
-        let entry = entry?;
-        if entry.file_type()?.is_dir() {
+    for entry in fs::read_dir(&pkg_dir).map_err(io_error)? {
+        let entry = entry.map_err(io_error)?;
+        if entry.file_type().map_err(io_error)?.is_dir() {
             // Get the directory name (e.g. "A.2.0.0")
             let dir_name = entry.file_name();
             let dir_str = dir_name.to_string_lossy();
@@ -388,7 +511,8 @@ pub fn available_versions_from_repo(
                 &dir_str
             };
             // Parse the version string into an OpamVersion.
-            let version = OpamVersion::from_str(ver_str)?;
+            let version = OpamVersion::from_str(ver_str)
+                .map_err(|e| Error::from(ErrorKind::Syntax(e)).with_path(&pkg_dir))?;
             versions.push(version);
         }
     }
@@ -398,6 +522,21 @@ pub fn available_versions_from_repo(
     Ok(versions)
 }
 
+/// Given a repository path, returns the names of all packages in it.
+pub fn packages_from_repo(repo_path: &str) -> Result<Vec<String>, Error> {
+    let repo_dir = Path::new(repo_path);
+    let io_error = |e: std::io::Error| Error::from(ErrorKind::Io(e.to_string())).with_path(repo_dir);
+    let mut packages = Vec::new();
+    for entry in fs::read_dir(repo_dir).map_err(io_error)? {
+        let entry = entry.map_err(io_error)?;
+        if entry.file_type().map_err(io_error)?.is_dir() {
+            packages.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    packages.sort();
+    Ok(packages)
+}
+
 fn get_depends(formula: Option<DependsField>) -> Vec<OpamPackageFormula> {
     match formula {
         Some(DependsField::Multiple(vec)) => vec,
@@ -406,50 +545,162 @@ fn get_depends(formula: Option<DependsField>) -> Vec<OpamPackageFormula> {
     }
 }
 
+fn get_conflict_classes(conflict_class: Option<ConflictClassField>) -> Vec<String> {
+    match conflict_class {
+        Some(ConflictClassField::Multiple(vec)) => vec,
+        Some(ConflictClassField::Single(class)) => vec![class],
+        None => vec![],
+    }
+}
+
+fn get_flags(flags: Option<FlagsField>) -> Vec<String> {
+    match flags {
+        Some(FlagsField::Multiple(vec)) => vec.into_iter().map(|flag| flag.id).collect(),
+        Some(FlagsField::Single(flag)) => vec![flag.id],
+        None => vec![],
+    }
+}
+
+/// Returns the `available` filter as a single formula, or `None` if the package
+/// version is always available.
+fn get_available(available: Option<AvailableField>) -> Result<Option<VersionFormula>, ErrorKind> {
+    let filters = match available {
+        Some(AvailableField::Multiple(vec)) => vec,
+        Some(AvailableField::Single(filter)) => vec![filter],
+        None => vec![],
+    };
+    Ok(filters
+        .iter()
+        .map(parse_filter_expr)
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .reduce(|lhs, rhs| {
+            VersionFormula::And(Binary {
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            })
+        }))
+}
+
+/// The file that `read_opam_file` reads for a package version: its `opam.json` if
+/// there is one, and otherwise its `opam` file.
+pub fn opam_file_path(pkg_dir: &Path) -> PathBuf {
+    let json_file = pkg_dir.join("opam.json");
+    if json_file.exists() {
+        json_file
+    } else {
+        pkg_dir.join("opam")
+    }
+}
+
+/// Reads the opam file of a package version.
+///
+/// An `opam.json` produced by `generate-repo.sh` is used when present, otherwise
+/// the plain `opam` file is parsed directly, so an untouched opam-repository
+/// checkout can be used as is.
+pub fn read_opam_file(pkg_dir: &Path) -> Result<OpamJson, Error> {
+    let path = opam_file_path(pkg_dir);
+    let content = fs::read_to_string(&path)
+        .map_err(|e| Error::from(ErrorKind::Io(e.to_string())).with_path(&path))?;
+    if path.ends_with("opam.json") {
+        return serde_json::from_str(&content)
+            .map_err(|e| Error::from(ErrorKind::Syntax(e.to_string())).with_path(&path));
+    }
+    let value =
+        parse_opam(&content).map_err(|e| Error::from(ErrorKind::Syntax(e)).with_path(&path))?;
+    serde_json::from_value(value)
+        .map_err(|e| Error::from(ErrorKind::Syntax(e.to_string())).with_path(&path))
+}
+
+/// Reads a profile of variable values, written as opam fields, e.g.
+///
+/// ```text
+/// os: "linux"
+/// arch: "x86_64"
+/// os-family: "debian"
+/// ```
+pub fn read_profile(path: &Path) -> Result<Vec<(String, OpamVersion)>, Error> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| Error::from(ErrorKind::Io(e.to_string())).with_path(path))?;
+    let value =
+        parse_opam(&content).map_err(|e| Error::from(ErrorKind::Syntax(e)).with_path(path))?;
+    let fields = match value {
+        serde_json::Value::Object(fields) => fields,
+        _ => return Err(Error::from(ErrorKind::Syntax("expected fields".to_string())).with_path(path)),
+    };
+    fields
+        .into_iter()
+        .map(|(var, value)| {
+            let value = match value {
+                serde_json::Value::String(s) => s,
+                serde_json::Value::Bool(b) => b.to_string(),
+                serde_json::Value::Number(n) => n.to_string(),
+                _ => {
+                    let msg = format!("unsupported value for variable {}: {}", var, value);
+                    return Err(Error::from(ErrorKind::Syntax(msg)).with_path(path));
+                }
+            };
+            Ok((var, OpamVersion(value)))
+        })
+        .collect()
+}
+
+/// The fields of a package version's opam file that the solver uses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpamFile {
+    /// The `depends`, `depopts`, `conflicts` and `conflict-class` formulas.
+    pub dependencies: Vec<PackageFormula>,
+    /// The `available` filter, or `None` if the version is always available.
+    pub available: Option<VersionFormula>,
+    pub conflict_classes: Vec<String>,
+    /// The flags of the version, e.g. `avoid-version`.
+    pub flags: Vec<String>,
+    /// The file these were read from, `opam.json` or `opam`.
+    pub path: PathBuf,
+}
+
 /// Given a repository path, package name, and version,
-/// returns the dependency formulas for that package version.
-pub fn parse_dependencies_for_package_version(
+/// reads and converts the opam file of that package version.
+pub fn parse_package_version(
     repo_path: &str,
     package: &str,
     version: &str,
-) -> Result<Vec<PackageFormula>, Box<dyn Error>> {
+) -> Result<OpamFile, Error> {
     // Build the expected directory path.
     // For example:
-    //   repo_path/packages/A/A.2.0.0/opam.json
+    //   repo_path/packages/A/A.2.0.0/
     let pkg_dir = Path::new(repo_path)
         .join(package)
         .join(format!("{}.{}", package, version));
-    let opam_file = pkg_dir.join("opam.json");
-
-    // Read the opam file.
-    let content = fs::read_to_string(&opam_file)
-        .map_err(|e| format!("Failed to read {}: {}", opam_file.display(), e))?;
-
-    // Parse the JSON into an OpamJson struct.
-    let opam_data: OpamJson = serde_json::from_str(&content).map_err(|e| {
-        format!(
-            "Error parsing {}: {}\nContent:\n{}",
-            opam_file.display(),
-            e,
-            content
-        )
-    })?;
+    let opam_data = read_opam_file(&pkg_dir)?;
+    let path = opam_file_path(&pkg_dir);
+    let formula_error = |kind: ErrorKind| Error::from(kind).with_path(&path);
 
     // Convert the dependency formulas, if any.
-    let mut dependencies: Vec<PackageFormula> = get_depends(opam_data.depends)
-        .into_iter()
-        .map(|pf| parse_package_formula(&pf))
-        .collect();
-
-    match opam_data.conflict_class {
-        Some(conflict_class) => {
-            dependencies.push(PackageFormula::ConflictClass  {
-                name: conflict_class.clone(),
-                package: package.to_string(),
-            });
-        }
-        _ => ()
+    let mut dependencies: Vec<PackageFormula> = Vec::new();
+    for pf in get_depends(opam_data.depends) {
+        dependencies.extend(parse_package_formula(&pf).map_err(formula_error)?);
+    }
+    for pf in get_depends(opam_data.depopts) {
+        dependencies.extend(parse_package_formula(&pf).map_err(formula_error)?.map(depopts_of));
+    }
+    for pf in get_depends(opam_data.conflicts) {
+        dependencies.extend(parse_package_formula(&pf).map_err(formula_error)?.map(conflicts_of));
+    }
+
+    let conflict_classes = get_conflict_classes(opam_data.conflict_class);
+    for conflict_class in &conflict_classes {
+        dependencies.push(PackageFormula::ConflictClass {
+            name: conflict_class.clone(),
+            package: package.to_string(),
+        });
     }
 
-    Ok(dependencies)
+    Ok(OpamFile {
+        dependencies,
+        available: get_available(opam_data.available).map_err(formula_error)?,
+        conflict_classes,
+        flags: get_flags(opam_data.flags),
+        path,
+    })
 }
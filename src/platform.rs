@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::fs;
+use std::process::Command;
+
+use crate::opam_version::OpamVersion;
+
+/// Detects the `os`, `arch`, `os-family`, `os-distribution` and `os-version` variables
+/// of the machine we're running on, normalised as opam does. Variables that can't be
+/// determined are left out.
+pub fn host_variables() -> Vec<(String, OpamVersion)> {
+    let os = normalise_os(
+        &command_output("uname", &["-s"]).unwrap_or_else(|| std::env::consts::OS.to_string()),
+    );
+    let arch = normalise_arch(
+        &command_output("uname", &["-m"]).unwrap_or_else(|| std::env::consts::ARCH.to_string()),
+    );
+    let os_release = fs::read_to_string("/etc/os-release")
+        .or_else(|_| fs::read_to_string("/usr/lib/os-release"))
+        .map(|content| parse_os_release(&content))
+        .unwrap_or_default();
+
+    let (distribution, version) = match os.as_str() {
+        "linux" => (
+            os_release.get("ID").map(|id| id.to_lowercase()),
+            os_release.get("VERSION_ID").cloned(),
+        ),
+        "macos" => (
+            Some(macos_distribution()),
+            command_output("sw_vers", &["-productVersion"]),
+        ),
+        "freebsd" | "openbsd" | "netbsd" | "dragonfly" => {
+            (Some(os.clone()), command_output("uname", &["-r"]))
+        }
+        _ => (Some(os.clone()), None),
+    };
+    let family = os_family(&os, &os_release, distribution.as_deref());
+
+    let mut variables = vec![
+        ("os".to_string(), OpamVersion(os)),
+        ("arch".to_string(), OpamVersion(arch)),
+    ];
+    let optional = [
+        ("os-family", family),
+        ("os-distribution", distribution),
+        ("os-version", version),
+    ];
+    for (var, value) in optional {
+        if let Some(value) = value {
+            variables.push((var.to_string(), OpamVersion(value)));
+        }
+    }
+    variables
+}
+
+fn command_output(command: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(command).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    let output = output.trim();
+    if output.is_empty() {
+        None
+    } else {
+        Some(output.to_string())
+    }
+}
+
+/// Normalises the output of `uname -s` to opam's `os` values.
+pub fn normalise_os(raw: &str) -> String {
+    let raw = raw.trim().to_lowercase();
+    match raw.as_str() {
+        "darwin" | "osx" => "macos".to_string(),
+        "windows" => "win32".to_string(),
+        _ if raw.starts_with("cygwin") => "cygwin".to_string(),
+        _ if raw.starts_with("mingw") || raw.starts_with("msys") => "win32".to_string(),
+        _ => raw,
+    }
+}
+
+/// Normalises the output of `uname -m` to opam's `arch` values.
+pub fn normalise_arch(raw: &str) -> String {
+    let raw = raw.trim().to_lowercase();
+    match raw.as_str() {
+        "x86" | "i386" | "i486" | "i586" | "i686" => "x86_32".to_string(),
+        "x86_64" | "amd64" => "x86_64".to_string(),
+        "powerpc" | "ppc" | "ppcle" => "ppc32".to_string(),
+        "ppc64" | "ppc64le" => "ppc64".to_string(),
+        "aarch64_be" | "aarch64" => "arm64".to_string(),
+        "armv8b" | "armv8l" => "arm32".to_string(),
+        _ if ["armv5", "armv6", "earmv6", "armv7", "earmv7"]
+            .iter()
+            .any(|prefix| raw.starts_with(prefix)) =>
+        {
+            "arm32".to_string()
+        }
+        _ => raw,
+    }
+}
+
+/// Parses the `KEY=value` lines of an `os-release` file, unquoting values.
+pub fn parse_os_release(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(value);
+            (key.trim().to_string(), value.to_string())
+        })
+        .collect()
+}
+
+/// On macOS opam reports the package manager in use as the distribution.
+fn macos_distribution() -> String {
+    if command_output("brew", &["--prefix"]).is_some() {
+        "homebrew".to_string()
+    } else if command_output("port", &["version"]).is_some() {
+        "macports".to_string()
+    } else {
+        "macos".to_string()
+    }
+}
+
+/// The `os-family`: the first distribution a Linux distribution is like, `bsd` or
+/// `windows` for those systems, and otherwise the distribution.
+pub fn os_family(
+    os: &str,
+    os_release: &HashMap<String, String>,
+    distribution: Option<&str>,
+) -> Option<String> {
+    match os {
+        "linux" => os_release
+            .get("ID_LIKE")
+            .and_then(|like| like.split_whitespace().next())
+            .map(|like| like.to_lowercase())
+            .or_else(|| distribution.map(str::to_string)),
+        "freebsd" | "openbsd" | "netbsd" | "dragonfly" => Some("bsd".to_string()),
+        "win32" | "cygwin" => Some("windows".to_string()),
+        _ => distribution.map(str::to_string),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalise() {
+        assert_eq!(normalise_os("Darwin"), "macos");
+        assert_eq!(normalise_os("Linux"), "linux");
+        assert_eq!(normalise_os("FreeBSD"), "freebsd");
+        assert_eq!(normalise_os("CYGWIN_NT-10.0"), "cygwin");
+        assert_eq!(normalise_os("MINGW64_NT-10.0"), "win32");
+        assert_eq!(normalise_os(" linux\n"), "linux");
+        assert_eq!(normalise_arch("x86_64"), "x86_64");
+        assert_eq!(normalise_arch("amd64"), "x86_64");
+        assert_eq!(normalise_arch("aarch64"), "arm64");
+        assert_eq!(normalise_arch("arm64"), "arm64");
+        assert_eq!(normalise_arch("armv7l"), "arm32");
+        assert_eq!(normalise_arch("i686"), "x86_32");
+        assert_eq!(normalise_arch("ppc64le"), "ppc64");
+        assert_eq!(normalise_arch("armv6l"), "arm32");
+        assert_eq!(normalise_arch("riscv64"), "riscv64");
+    }
+
+    #[test]
+    fn test_os_release() {
+        let os_release = parse_os_release(
+            "NAME=\"Ubuntu\"\nVERSION_ID=\"24.04\"\nID=ubuntu\nID_LIKE=debian\n# comment\n",
+        );
+        assert_eq!(os_release.get("ID").map(String::as_str), Some("ubuntu"));
+        assert_eq!(os_release.get("VERSION_ID").map(String::as_str), Some("24.04"));
+        assert_eq!(os_family("linux", &os_release, Some("ubuntu")).as_deref(), Some("debian"));
+
+        let os_release = parse_os_release("ID='rocky'\nID_LIKE=\"rhel centos fedora\"\n");
+        assert_eq!(os_family("linux", &os_release, Some("rocky")).as_deref(), Some("rhel"));
+
+        let os_release = parse_os_release("ID=arch\n");
+        assert_eq!(os_family("linux", &os_release, Some("arch")).as_deref(), Some("arch"));
+        assert_eq!(os_family("freebsd", &os_release, Some("freebsd")).as_deref(), Some("bsd"));
+    }
+
+    #[test]
+    fn test_host_variables() {
+        let variables: HashMap<String, OpamVersion> = host_variables().into_iter().collect();
+        assert!(variables.contains_key("os"));
+        assert!(variables.contains_key("arch"));
+    }
+}
@@ -0,0 +1,1086 @@
+use pubgrub::{
+    DefaultStringReporter, Dependencies, DependencyProvider, DerivationTree, Derived, External,
+    Map, PubGrubError, Range, ReportFormatter, Reporter, Term,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Bound::{Excluded, Included, Unbounded};
+
+use crate::error::Error;
+use crate::index::{PackageFormula, Solution, Solver, VersionFormula};
+use crate::opam_deps::{
+    defined_range, installed_range, Package, FALSE_VERSION, NOT_INSTALLED_VERSION, TRUE_VERSION,
+    UNDEFINED_VERSION,
+};
+use crate::opam_version::OpamVersion;
+
+/// Reports why a solve failed in terms of opam packages, formulas and variables rather
+/// than the synthetic packages they are encoded with.
+pub struct OpamReporter;
+
+impl Reporter<Package, Range<OpamVersion>, String> for OpamReporter {
+    type Output = String;
+
+    fn report(derivation_tree: &DerivationTree<Package, Range<OpamVersion>, String>) -> String {
+        Self::report_with_formatter(derivation_tree, &OpamReportFormatter::default())
+    }
+
+    fn report_with_formatter(
+        derivation_tree: &DerivationTree<Package, Range<OpamVersion>, String>,
+        formatter: &impl ReportFormatter<Package, Range<OpamVersion>, String, Output = String>,
+    ) -> String {
+        DefaultStringReporter::report_with_formatter(derivation_tree, formatter)
+    }
+}
+
+/// Formats the incompatibilities of a report. With the solver that failed, each
+/// dependency also names the opam file it comes from.
+#[derive(Default)]
+pub struct OpamReportFormatter<'a> {
+    solver: Option<&'a Solver>,
+}
+
+impl<'a> OpamReportFormatter<'a> {
+    pub fn new(solver: &'a Solver) -> Self {
+        Self {
+            solver: Some(solver),
+        }
+    }
+
+    /// The package version and opam file that a package at the versions in `range`
+    /// comes from, if it's a single file that we know of.
+    fn origin(&self, package: &Package, range: &Range<OpamVersion>) -> Option<JsonOrigin> {
+        let solver = self.solver?;
+        let version = range.as_singleton()?;
+        let (package, version) = solver.origin(package, version)?;
+        let file = solver.index().repository.opam_file(&package, &version).ok()?.path.clone();
+        Some(JsonOrigin {
+            package,
+            version: version.to_string(),
+            file: file.display().to_string(),
+        })
+    }
+
+    fn format_term(&self, package: &Package, term: &Term<Range<OpamVersion>>) -> String {
+        match term {
+            Term::Positive(range) => describe(package, range),
+            Term::Negative(range) => describe(package, &range.complement()),
+        }
+    }
+}
+
+impl ReportFormatter<Package, Range<OpamVersion>, String> for OpamReportFormatter<'_> {
+    type Output = String;
+
+    fn format_external(&self, external: &External<Package, Range<OpamVersion>, String>) -> String {
+        match external {
+            External::NotRoot(Package::Root(_), _) => {
+                "we are solving dependencies of the request".to_string()
+            }
+            External::NotRoot(package, version) => format!(
+                "we are solving dependencies of {}",
+                describe(package, &Range::singleton(version.clone()))
+            ),
+            External::NoVersions(Package::Base(name), _)
+                if self
+                    .solver
+                    .is_some_and(|solver| solver.missing_packages().contains(name)) =>
+            {
+                format!("package {} is not in the repository", name)
+            }
+            External::NoVersions(package, range) => {
+                format!("there is no {}", describe(package, range))
+            }
+            External::Custom(package, range, reason) => {
+                let mut formatted = format!("{} is unavailable: {}", describe(package, range), reason);
+                if let Some(origin) = self.origin(package, range) {
+                    formatted.push_str(&format!(" (in {})", origin.file));
+                }
+                formatted
+            }
+            External::FromDependencyOf(Package::Root(_), _, dependency, dependency_range) => {
+                format!(
+                    "the request depends on {}",
+                    describe(dependency, dependency_range)
+                )
+            }
+            External::FromDependencyOf(package, range, dependency, dependency_range) => {
+                let mut formatted = format!(
+                    "{} depends on {}",
+                    describe(package, range),
+                    describe(dependency, dependency_range)
+                );
+                if let Some(origin) = self.origin(package, range) {
+                    formatted.push_str(&format!(" (in {})", origin.file));
+                }
+                formatted
+            }
+        }
+    }
+
+    fn format_terms(&self, terms: &Map<Package, Term<Range<OpamVersion>>>) -> String {
+        let mut terms: Vec<_> = terms.iter().collect();
+        terms.sort_by_key(|(package, term)| self.format_term(package, term));
+        match terms.as_slice() {
+            [] => "version solving failed".to_string(),
+            [(Package::Root(_), Term::Positive(_))] => "the request can't be satisfied".to_string(),
+            [(package, Term::Positive(range))] => {
+                format!("{} is forbidden", describe(package, range))
+            }
+            [(package, Term::Negative(range))] => {
+                format!("{} is mandatory", describe(package, range))
+            }
+            [(p1, Term::Positive(r1)), (p2, Term::Negative(r2))]
+            | [(p2, Term::Negative(r2)), (p1, Term::Positive(r1))] => self.format_external(
+                &External::FromDependencyOf((*p1).clone(), r1.clone(), (*p2).clone(), r2.clone()),
+            ),
+            terms => {
+                let terms: Vec<_> = terms
+                    .iter()
+                    .map(|(package, term)| self.format_term(package, term))
+                    .collect();
+                format!("{} are incompatible", terms.join(", "))
+            }
+        }
+    }
+
+    fn explain_both_external(
+        &self,
+        external1: &External<Package, Range<OpamVersion>, String>,
+        external2: &External<Package, Range<OpamVersion>, String>,
+        current_terms: &Map<Package, Term<Range<OpamVersion>>>,
+    ) -> String {
+        format!(
+            "Because {} and {}, {}.",
+            self.format_external(external1),
+            self.format_external(external2),
+            self.format_terms(current_terms)
+        )
+    }
+
+    fn explain_both_ref(
+        &self,
+        ref_id1: usize,
+        derived1: &Derived<Package, Range<OpamVersion>, String>,
+        ref_id2: usize,
+        derived2: &Derived<Package, Range<OpamVersion>, String>,
+        current_terms: &Map<Package, Term<Range<OpamVersion>>>,
+    ) -> String {
+        format!(
+            "Because {} ({}) and {} ({}), {}.",
+            self.format_terms(&derived1.terms),
+            ref_id1,
+            self.format_terms(&derived2.terms),
+            ref_id2,
+            self.format_terms(current_terms)
+        )
+    }
+
+    fn explain_ref_and_external(
+        &self,
+        ref_id: usize,
+        derived: &Derived<Package, Range<OpamVersion>, String>,
+        external: &External<Package, Range<OpamVersion>, String>,
+        current_terms: &Map<Package, Term<Range<OpamVersion>>>,
+    ) -> String {
+        format!(
+            "Because {} ({}) and {}, {}.",
+            self.format_terms(&derived.terms),
+            ref_id,
+            self.format_external(external),
+            self.format_terms(current_terms)
+        )
+    }
+
+    fn and_explain_external(
+        &self,
+        external: &External<Package, Range<OpamVersion>, String>,
+        current_terms: &Map<Package, Term<Range<OpamVersion>>>,
+    ) -> String {
+        format!(
+            "And because {}, {}.",
+            self.format_external(external),
+            self.format_terms(current_terms)
+        )
+    }
+
+    fn and_explain_ref(
+        &self,
+        ref_id: usize,
+        derived: &Derived<Package, Range<OpamVersion>, String>,
+        current_terms: &Map<Package, Term<Range<OpamVersion>>>,
+    ) -> String {
+        format!(
+            "And because {} ({}), {}.",
+            self.format_terms(&derived.terms),
+            ref_id,
+            self.format_terms(current_terms)
+        )
+    }
+
+    fn and_explain_prior_and_external(
+        &self,
+        prior_external: &External<Package, Range<OpamVersion>, String>,
+        external: &External<Package, Range<OpamVersion>, String>,
+        current_terms: &Map<Package, Term<Range<OpamVersion>>>,
+    ) -> String {
+        format!(
+            "And because {} and {}, {}.",
+            self.format_external(prior_external),
+            self.format_external(external),
+            self.format_terms(current_terms)
+        )
+    }
+}
+
+/// A derivation tree as a JSON document, for tools to render. Each incompatibility is
+/// a node, listed after the nodes it was derived from, so `root` is the last.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JsonReport {
+    pub root: usize,
+    pub nodes: Vec<JsonNode>,
+    /// The packages the solve needed that aren't in the repository.
+    pub missing: Vec<JsonMissing>,
+}
+
+/// A package that isn't in the repository, with the packages that are whose names are
+/// close to it, closest first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JsonMissing {
+    pub package: String,
+    pub suggestions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JsonNodeKind {
+    /// The request itself.
+    NotRoot,
+    /// A package that has no versions in a range.
+    NoVersions,
+    /// A package version that can't be installed, with the reason.
+    Unavailable,
+    /// A package version that depends on another package.
+    Dependency,
+    /// An incompatibility derived from its two causes.
+    Derived,
+}
+
+/// An incompatibility: a set of terms that can't all hold.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JsonNode {
+    pub id: usize,
+    pub kind: JsonNodeKind,
+    pub terms: Vec<JsonTerm>,
+    /// The package version and opam file the incompatibility comes from, if it's one.
+    pub origin: Option<JsonOrigin>,
+    pub reason: Option<String>,
+    /// The incompatibility as a sentence, as in the text report.
+    pub description: String,
+    /// The ids of the nodes this one was derived from.
+    pub causes: Vec<usize>,
+}
+
+/// A package (or variable, or formula) being in the versions of `constraint`, or with
+/// `negated` not being in them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JsonTerm {
+    pub package: String,
+    pub constraint: String,
+    pub negated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JsonOrigin {
+    pub package: String,
+    pub version: String,
+    pub file: String,
+}
+
+impl OpamReportFormatter<'_> {
+    /// The derivation tree of a failed solve as a JSON document, with the packages
+    /// missing from the repository that it needed.
+    pub fn json_report(
+        &self,
+        derivation_tree: &DerivationTree<Package, Range<OpamVersion>, String>,
+    ) -> Result<JsonReport, Error> {
+        let mut nodes = vec![];
+        let root = self.add_json_node(derivation_tree, &mut nodes, &mut Map::default());
+        let mut missing = vec![];
+        if let Some(solver) = self.solver {
+            for package in solver.missing_packages_in(derivation_tree) {
+                let suggestions = solver.index().suggest_packages(&package)?;
+                missing.push(JsonMissing {
+                    package,
+                    suggestions,
+                });
+            }
+        }
+        Ok(JsonReport {
+            root,
+            nodes,
+            missing,
+        })
+    }
+
+    /// Adds the node for a tree after those of its causes, once for a shared subtree,
+    /// returning its id.
+    fn add_json_node(
+        &self,
+        tree: &DerivationTree<Package, Range<OpamVersion>, String>,
+        nodes: &mut Vec<JsonNode>,
+        shared: &mut Map<usize, usize>,
+    ) -> usize {
+        let term = |package: &Package, range: &Range<OpamVersion>, negated: bool| JsonTerm {
+            package: match package {
+                Package::Base(name) | Package::Var(name) => name.clone(),
+                _ => describe(package, &Range::full()),
+            },
+            constraint: describe(package, range),
+            negated,
+        };
+        let node = match tree {
+            DerivationTree::External(external) => {
+                let (kind, terms, origin, reason) = match external {
+                    External::NotRoot(package, version) => (
+                        JsonNodeKind::NotRoot,
+                        vec![term(package, &Range::singleton(version.clone()), true)],
+                        None,
+                        None,
+                    ),
+                    External::NoVersions(package, range) => (
+                        JsonNodeKind::NoVersions,
+                        vec![term(package, range, false)],
+                        None,
+                        None,
+                    ),
+                    External::Custom(package, range, reason) => (
+                        JsonNodeKind::Unavailable,
+                        vec![term(package, range, false)],
+                        self.origin(package, range),
+                        Some(reason.clone()),
+                    ),
+                    External::FromDependencyOf(package, range, dependency, dependency_range) => (
+                        JsonNodeKind::Dependency,
+                        vec![
+                            term(package, range, false),
+                            term(dependency, dependency_range, true),
+                        ],
+                        self.origin(package, range),
+                        None,
+                    ),
+                };
+                JsonNode {
+                    id: 0,
+                    kind,
+                    terms,
+                    origin,
+                    reason,
+                    description: self.format_external(external),
+                    causes: vec![],
+                }
+            }
+            DerivationTree::Derived(derived) => {
+                if let Some(id) = derived
+                    .shared_id
+                    .and_then(|shared_id| shared.get(&shared_id))
+                {
+                    return *id;
+                }
+                let causes = vec![
+                    self.add_json_node(&derived.cause1, nodes, shared),
+                    self.add_json_node(&derived.cause2, nodes, shared),
+                ];
+                let mut terms: Vec<_> = derived
+                    .terms
+                    .iter()
+                    .map(|(package, t)| match t {
+                        Term::Positive(range) => term(package, range, false),
+                        Term::Negative(range) => term(package, range, true),
+                    })
+                    .collect();
+                terms.sort_by(|a, b| (&a.package, &a.constraint).cmp(&(&b.package, &b.constraint)));
+                JsonNode {
+                    id: 0,
+                    kind: JsonNodeKind::Derived,
+                    terms,
+                    origin: None,
+                    reason: None,
+                    description: self.format_terms(&derived.terms),
+                    causes,
+                }
+            }
+        };
+        let id = nodes.len();
+        nodes.push(JsonNode { id, ..node });
+        if let DerivationTree::Derived(Derived {
+            shared_id: Some(shared_id),
+            ..
+        }) = tree
+        {
+            shared.insert(*shared_id, id);
+        }
+        id
+    }
+}
+
+/// The version of the format of `JsonDocument`, raised when a field is removed or
+/// changes meaning. Fields may be added without raising it.
+pub const JSON_VERSION: u32 = 1;
+
+/// The outcome of a solve as a JSON document, for tools to consume: `version` is that
+/// of the format, and the fields of the outcome are tagged by `result`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JsonDocument {
+    pub version: u32,
+    #[serde(flatten)]
+    pub outcome: JsonOutcome,
+}
+
+impl JsonDocument {
+    /// The document for the outcome of a solve, or for the error if the outcome can't
+    /// be described, e.g. as an opam file of the solution can't be read.
+    pub fn new(solver: &Solver, result: &Result<Solution, PubGrubError<Solver>>) -> Self {
+        let outcome = JsonOutcome::new(solver, result).unwrap_or_else(|err| {
+            JsonOutcome::Error(JsonError {
+                message: err.to_string(),
+                package: err.package.clone(),
+                version: err.version.as_ref().map(|version| version.to_string()),
+            })
+        });
+        JsonDocument {
+            version: JSON_VERSION,
+            outcome,
+        }
+    }
+}
+
+/// The outcome of a solve: the solution, the explanation of why there's none, or the
+/// error the solve stopped on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+pub enum JsonOutcome {
+    Solved(JsonSolution),
+    NoSolution(JsonReport),
+    Error(JsonError),
+}
+
+impl JsonOutcome {
+    pub fn new(
+        solver: &Solver,
+        result: &Result<Solution, PubGrubError<Solver>>,
+    ) -> Result<Self, Error> {
+        Ok(match result {
+            Ok(sol) => {
+                let mut packages = vec![];
+                for ((name, version), dependencies) in resolved_graph(solver, sol) {
+                    packages.push(JsonPackage {
+                        deprecated: solver.index().is_deprecated(&name, version)?,
+                        name,
+                        version: version.to_string(),
+                        dependencies: dependencies
+                            .into_iter()
+                            .map(|(name, version)| JsonDependency {
+                                name,
+                                version: version.to_string(),
+                            })
+                            .collect(),
+                    });
+                }
+                let variables = sol
+                    .iter()
+                    .filter_map(|(package, version)| match package {
+                        Package::Var(name) => Some((name.clone(), version.to_string())),
+                        _ => None,
+                    })
+                    .collect();
+                JsonOutcome::Solved(JsonSolution {
+                    packages,
+                    variables,
+                })
+            }
+            Err(PubGrubError::NoSolution(derivation_tree)) => JsonOutcome::NoSolution(
+                OpamReportFormatter::new(solver).json_report(derivation_tree)?,
+            ),
+            Err(err) => JsonOutcome::Error(JsonError::new(err)),
+        })
+    }
+}
+
+/// The installed packages, each with the packages it depends on, and the values of
+/// the variables the solver chose.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JsonSolution {
+    pub packages: Vec<JsonPackage>,
+    pub variables: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JsonPackage {
+    pub name: String,
+    pub version: String,
+    pub deprecated: bool,
+    pub dependencies: Vec<JsonDependency>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JsonDependency {
+    pub name: String,
+    pub version: String,
+}
+
+/// A solve that failed other than for want of a solution, e.g. on an opam file that
+/// can't be read, with the package version it failed on if there's one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JsonError {
+    pub message: String,
+    pub package: Option<String>,
+    pub version: Option<String>,
+}
+
+impl JsonError {
+    pub fn new(err: &PubGrubError<Solver>) -> Self {
+        let (package, version, source) = match err {
+            PubGrubError::NoSolution(_) => (None, None, None),
+            PubGrubError::ErrorRetrievingDependencies {
+                package,
+                version,
+                source,
+            } => (
+                Some(package.to_string()),
+                Some(version.to_string()),
+                Some(source),
+            ),
+            PubGrubError::ErrorChoosingVersion { package, source } => {
+                (Some(package.to_string()), None, Some(source))
+            }
+            PubGrubError::ErrorInShouldCancel(source) => (None, None, Some(source)),
+        };
+        JsonError {
+            message: match source {
+                Some(source) => format!("{}: {}", err, source),
+                None => err.to_string(),
+            },
+            package,
+            version,
+        }
+    }
+}
+
+/// The installed packages in a solution, each with the packages it depends on.
+pub fn resolved_graph<'a>(
+    solver: &'a Solver,
+    sol: &'a Solution,
+) -> BTreeMap<(String, &'a OpamVersion), Vec<(String, &'a OpamVersion)>> {
+    let mut resolved_graph = BTreeMap::new();
+    for (package, version) in sol {
+        if let Package::Base(name) = package {
+            let mut deps = get_resolved_deps(solver, sol, package, version)
+                .into_iter()
+                .collect::<Vec<_>>();
+            deps.sort_by(|(p1, _v1), (p2, _v2)| p1.cmp(p2));
+            resolved_graph.insert((name.clone(), version), deps);
+        }
+    }
+    resolved_graph
+}
+
+/// The packages (and variables) that `package` depends on in the solution, looking
+/// through the synthetic packages used to encode formulas.
+pub fn get_resolved_deps<'a>(
+    solver: &'a Solver,
+    sol: &'a Solution,
+    package: &Package,
+    version: &'a OpamVersion,
+) -> HashSet<(String, &'a OpamVersion)> {
+    let dependencies = solver.get_dependencies(package, version);
+    match dependencies {
+        Ok(Dependencies::Available(constraints)) => {
+            let mut dependents = HashSet::new();
+            for (dep_package, _dep_versions) in constraints {
+                // a conflict constrains a package without depending on it
+                if solver.is_conflict(package, version, &dep_package) {
+                    continue;
+                }
+                // packages that aren't installed aren't in the solution
+                let Some(solved_version) = sol.get(&dep_package) else {
+                    continue;
+                };
+                match &dep_package {
+                    Package::Base(name) => {
+                        dependents.insert((name.clone(), solved_version));
+                    }
+                    Package::Var(_) => {
+                        dependents.insert((dep_package.to_string(), solved_version));
+                    }
+                    // look through the synthetic packages to what they encode
+                    _ => dependents.extend(get_resolved_deps(
+                        solver,
+                        sol,
+                        &dep_package,
+                        solved_version,
+                    )),
+                }
+            }
+            dependents
+        }
+        _ => {
+            eprintln!("No available dependencies for package {}", package);
+            HashSet::new()
+        }
+    }
+}
+
+/// A package at the versions in `range`, in opam's terms: a constrained package, a
+/// variable's values, or the formula a synthetic package stands for.
+pub fn describe(package: &Package, range: &Range<OpamVersion>) -> String {
+    match package {
+        Package::Root(_) => "the request".to_string(),
+        Package::Base(name) if range.is_empty() => format!("no version of {}", name),
+        Package::Base(name) => match format_versions(range) {
+            Some(versions) => format!("{} {}", name, versions),
+            None => name.clone(),
+        },
+        Package::Var(var) => {
+            let defined = range.intersection(&defined_range());
+            let undefined = range.contains(&UNDEFINED_VERSION);
+            let values = if defined == Range::singleton(TRUE_VERSION.clone()) {
+                Some(var.clone())
+            } else if defined == Range::singleton(FALSE_VERSION.clone()) {
+                Some(format!("!{}", var))
+            } else if defined.is_empty() {
+                None
+            } else {
+                format_range(&defined, var).or_else(|| Some(format!("?{}", var)))
+            };
+            match (values, undefined) {
+                (None, false) => format!("no value of {}", var),
+                (None, true) => format!("{} undefined", var),
+                (Some(values), false) => values,
+                (Some(values), true) => format!("{} or {} undefined", values, var),
+            }
+        }
+        Package::ConflictClass(class) => match range.as_singleton() {
+            Some(member) => format!("{} in conflict class {}", member, class),
+            None => format!("conflict class {}", class),
+        },
+        Package::Lor { lhs, rhs } => {
+            let or = format!(
+                "{} | {}",
+                format_package_formula(lhs),
+                format_package_formula(rhs)
+            );
+            match range
+                .as_singleton()
+                .map(|version| version.to_string())
+                .as_deref()
+            {
+                Some("lhs") => format!("{} of {}", format_package_formula(lhs), or),
+                Some("rhs") => format!("{} of {}", format_package_formula(rhs), or),
+                _ => or,
+            }
+        }
+        Package::Formula { name, formula } => {
+            let formula = format!("{} {{{}}}", name, format_version_formula(formula));
+            match range.as_singleton() {
+                Some(version) if *version == *TRUE_VERSION => formula,
+                Some(version) if *version == *FALSE_VERSION => format!("not {}", formula),
+                _ => formula,
+            }
+        }
+        Package::Proxy { name, formula } => {
+            let described = match name {
+                Some(name) => format!("{} {{{}}}", name, format_version_formula(formula)),
+                None => format!("{{{}}}", format_version_formula(formula)),
+            };
+            match (&**formula, range.as_singleton()) {
+                (VersionFormula::Or(binary), Some(version)) if version.to_string() == "lhs" => {
+                    format!("{} of {}", format_version_formula(&binary.lhs), described)
+                }
+                (VersionFormula::Or(binary), Some(version)) if version.to_string() == "rhs" => {
+                    format!("{} of {}", format_version_formula(&binary.rhs), described)
+                }
+                (VersionFormula::Comparator { relop: _, binary }, _) if *range != Range::full() => {
+                    let lhs = format_version_formula(&binary.lhs);
+                    match format_range(range, &lhs) {
+                        Some(values) => format!("{} with {}", described, values),
+                        None => described,
+                    }
+                }
+                _ => described,
+            }
+        }
+    }
+}
+
+/// The versions of a package in `range`, including whether it may be left out, or
+/// `None` for any version as long as it's installed.
+fn format_versions(range: &Range<OpamVersion>) -> Option<String> {
+    let installed = range.intersection(&installed_range());
+    let not_installed = range.contains(&NOT_INSTALLED_VERSION);
+    match format_range(&installed, "") {
+        _ if installed.is_empty() && not_installed => Some("not installed".to_string()),
+        _ if installed.is_empty() => Some("no version".to_string()),
+        None if not_installed => Some("installed or not".to_string()),
+        None => None,
+        Some(constraint) if not_installed => Some(format!("{} or not installed", constraint)),
+        Some(constraint) => Some(constraint),
+    }
+}
+
+/// A range in opam's constraint syntax, e.g. `>= 1.0 & < 2.0`, or `os = "linux"`
+/// for the values of a variable, or `None` for any version.
+pub fn format_range(range: &Range<OpamVersion>, variable: &str) -> Option<String> {
+    // the pseudo-versions split ranges that are otherwise continuous
+    let pseudo = |version: &OpamVersion| {
+        *version == *NOT_INSTALLED_VERSION || *version == *UNDEFINED_VERSION
+    };
+    let mut segments: Vec<(_, _)> = vec![];
+    for (lower, upper) in range.iter() {
+        match (segments.last_mut(), lower) {
+            (Some((_, Excluded(previous))), Excluded(next)) if previous == next && pseudo(next) => {
+                segments.last_mut().unwrap().1 = upper.clone();
+            }
+            _ => segments.push((lower.clone(), upper.clone())),
+        }
+    }
+    let constraint = |relop: &str, version: &OpamVersion| {
+        if variable.is_empty() {
+            format!("{} {}", relop, version)
+        } else {
+            format!("{} {} \"{}\"", variable, relop, version)
+        }
+    };
+    let formatted: Vec<String> = match segments.as_slice() {
+        [(Unbounded, Unbounded)] => return None,
+        [(Unbounded, Excluded(a)), (Excluded(b), Unbounded)] if a == b => vec![constraint("!=", a)],
+        segments => segments
+            .iter()
+            .map(|segment| match segment {
+                (Included(a), Included(b)) if a == b => constraint("=", a),
+                (lower, upper) => {
+                    let lower = match lower {
+                        Included(version) => Some(constraint(">=", version)),
+                        Excluded(version) => Some(constraint(">", version)),
+                        Unbounded => None,
+                    };
+                    let upper = match upper {
+                        Included(version) => Some(constraint("<=", version)),
+                        Excluded(version) => Some(constraint("<", version)),
+                        Unbounded => None,
+                    };
+                    lower
+                        .into_iter()
+                        .chain(upper)
+                        .collect::<Vec<_>>()
+                        .join(" & ")
+                }
+            })
+            .collect(),
+    };
+    Some(formatted.join(" | "))
+}
+
+/// A filtered version formula in opam's syntax, leaving out the constraints that the
+/// encoding adds for a dependency to be installed.
+pub fn format_version_formula(formula: &VersionFormula) -> String {
+    let installed = |formula: &VersionFormula| match formula {
+        VersionFormula::Version(range) => range.0 == installed_range(),
+        _ => false,
+    };
+    match formula {
+        VersionFormula::Version(range) => {
+            format_versions(&range.0).unwrap_or_else(|| "installed".to_string())
+        }
+        VersionFormula::Lit(literal) if *literal == *TRUE_VERSION || *literal == *FALSE_VERSION => {
+            literal.to_string()
+        }
+        VersionFormula::Lit(literal) => format!("\"{}\"", literal),
+        VersionFormula::Variable(var) => var.clone(),
+        VersionFormula::Not(var) => format!("!{}", var),
+        VersionFormula::Defined(var) => format!("?{}", var),
+        VersionFormula::Undefined(var) => format!("!?{}", var),
+        VersionFormula::And(binary) if installed(&binary.rhs) => {
+            format_version_formula(&binary.lhs)
+        }
+        VersionFormula::And(binary) if installed(&binary.lhs) => {
+            format_version_formula(&binary.rhs)
+        }
+        VersionFormula::And(binary) => format!(
+            "{} & {}",
+            format_operand(&binary.lhs),
+            format_operand(&binary.rhs)
+        ),
+        VersionFormula::Or(binary) => format!(
+            "{} | {}",
+            format_operand(&binary.lhs),
+            format_operand(&binary.rhs)
+        ),
+        VersionFormula::Comparator { relop, binary } => format!(
+            "{} {} {}",
+            format_operand(&binary.lhs),
+            relop,
+            format_operand(&binary.rhs)
+        ),
+        VersionFormula::Constraint { relop, arg } => format!("{} {}", relop, format_operand(arg)),
+    }
+}
+
+/// An operand of a binary formula, in parentheses if it's binary itself.
+fn format_operand(formula: &VersionFormula) -> String {
+    match formula {
+        VersionFormula::And(_) | VersionFormula::Or(_) | VersionFormula::Comparator { .. } => {
+            format!("({})", format_version_formula(formula))
+        }
+        _ => format_version_formula(formula),
+    }
+}
+
+/// A package formula in opam's syntax.
+pub fn format_package_formula(formula: &PackageFormula) -> String {
+    let with_formula = |name: &str, formula: &VersionFormula| match formula {
+        VersionFormula::Version(range) if range.0 == installed_range() => name.to_string(),
+        _ => format!("{} {{{}}}", name, format_version_formula(formula)),
+    };
+    match formula {
+        PackageFormula::Base { name, formula } => with_formula(name, formula),
+        PackageFormula::Conflict { name, formula } => {
+            format!("conflicts with {}", with_formula(name, formula))
+        }
+        PackageFormula::Depopt { name, formula } => {
+            format!("optionally {}", with_formula(name, formula))
+        }
+        PackageFormula::ConflictClass { name, package } => {
+            format!("{} in conflict class {}", package, name)
+        }
+        PackageFormula::And(binary) => format!(
+            "({} & {})",
+            format_package_formula(&binary.lhs),
+            format_package_formula(&binary.rhs)
+        ),
+        PackageFormula::Or(binary) => format!(
+            "({} | {})",
+            format_package_formula(&binary.lhs),
+            format_package_formula(&binary.rhs)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{Binary, HashedRange, Index};
+    use std::sync::Arc;
+
+    fn version(version: &str) -> OpamVersion {
+        OpamVersion(version.to_string())
+    }
+
+    fn dependency(name: &str) -> Box<PackageFormula> {
+        Box::new(PackageFormula::Base {
+            name: name.to_string(),
+            formula: VersionFormula::Version(HashedRange(installed_range())),
+        })
+    }
+
+    #[test]
+    fn test_format_range() {
+        let between = Range::between(version("1.0"), version("2.0"));
+        assert_eq!(
+            format_range(&between, "").as_deref(),
+            Some(">= 1.0 & < 2.0")
+        );
+        assert_eq!(
+            format_range(&between.complement(), "").as_deref(),
+            Some("< 1.0 | >= 2.0")
+        );
+        assert_eq!(
+            format_range(&Range::singleton(version("1.0")), "").as_deref(),
+            Some("= 1.0")
+        );
+        assert_eq!(
+            format_range(&Range::singleton(version("1.0")).complement(), "").as_deref(),
+            Some("!= 1.0")
+        );
+        assert_eq!(format_range(&Range::full(), ""), None);
+        assert_eq!(
+            format_range(&Range::singleton(version("linux")), "os").as_deref(),
+            Some("os = \"linux\"")
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        let a = Package::Base("A".to_string());
+        let between =
+            Range::between(version("1.0"), version("2.0")).intersection(&installed_range());
+        assert_eq!(describe(&a, &installed_range()), "A");
+        assert_eq!(describe(&a, &between), "A >= 1.0 & < 2.0");
+        assert_eq!(
+            describe(&a, &between.complement()),
+            "A < 1.0 | >= 2.0 or not installed"
+        );
+        assert_eq!(
+            describe(&a, &Range::singleton(NOT_INSTALLED_VERSION.clone())),
+            "A not installed"
+        );
+
+        let os = Package::Var("os".to_string());
+        assert_eq!(
+            describe(&os, &Range::singleton(version("linux"))),
+            "os = \"linux\""
+        );
+        assert_eq!(describe(&os, &defined_range()), "?os");
+        assert_eq!(
+            describe(&os, &Range::singleton(UNDEFINED_VERSION.clone())),
+            "os undefined"
+        );
+        let with_test = Package::Var("with-test".to_string());
+        assert_eq!(
+            describe(&with_test, &Range::singleton(TRUE_VERSION.clone())),
+            "with-test"
+        );
+
+        let formula = Package::Formula {
+            name: "B".to_string(),
+            formula: Box::new(VersionFormula::And(Binary {
+                lhs: Box::new(VersionFormula::Variable("with-test".to_string())),
+                rhs: Box::new(VersionFormula::Version(HashedRange(installed_range()))),
+            })),
+        };
+        assert_eq!(
+            describe(&formula, &Range::singleton(TRUE_VERSION.clone())),
+            "B {with-test}"
+        );
+        assert_eq!(
+            describe(&formula, &Range::singleton(FALSE_VERSION.clone())),
+            "not B {with-test}"
+        );
+
+        let lor = Package::Lor {
+            lhs: dependency("B"),
+            rhs: dependency("C"),
+        };
+        assert_eq!(
+            describe(&lor, &Range::singleton(version("rhs"))),
+            "C of B | C"
+        );
+        assert_eq!(describe(&lor, &Range::full()), "B | C");
+    }
+
+    /// The request depends on A >= 1.0 & < 2.0, which has no versions.
+    fn no_versions_tree() -> DerivationTree<Package, Range<OpamVersion>, String> {
+        let root = Package::Root(vec![]);
+        let a = Package::Base("A".to_string());
+        let between =
+            Range::between(version("1.0"), version("2.0")).intersection(&installed_range());
+        let mut terms = Map::default();
+        terms.insert(root.clone(), Term::Positive(Range::singleton(version(""))));
+        DerivationTree::Derived(Derived {
+            terms,
+            shared_id: None,
+            cause1: Arc::new(DerivationTree::External(External::FromDependencyOf(
+                root,
+                Range::singleton(version("")),
+                a.clone(),
+                between.clone(),
+            ))),
+            cause2: Arc::new(DerivationTree::External(External::NoVersions(a, between))),
+        })
+    }
+
+    #[test]
+    fn test_report() {
+        let report = OpamReporter::report(&no_versions_tree());
+        assert!(report.contains("the request depends on A >= 1.0 & < 2.0"));
+        assert!(report.contains("there is no A >= 1.0 & < 2.0"));
+        assert!(report.contains("the request can't be satisfied"));
+
+        // an unavailable version names the opam file it comes from
+        let solver = Index::new("./example-repo/packages".to_string()).solver();
+        let external = External::Custom(
+            Package::Base("F".to_string()),
+            Range::singleton(version("2.0.0")),
+            "not available on this platform: os = win32".to_string(),
+        );
+        let formatted = OpamReportFormatter::new(&solver).format_external(&external);
+        assert!(formatted.starts_with("F = 2.0.0 is unavailable: not available on this platform: os = win32 (in "));
+        assert!(formatted.ends_with("F/F.2.0.0/opam.json)"));
+    }
+
+    #[test]
+    fn test_json_report() {
+        let report = OpamReportFormatter::default().json_report(&no_versions_tree()).unwrap();
+        assert_eq!(report.root, 2);
+        let kinds: Vec<_> = report.nodes.iter().map(|node| node.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                JsonNodeKind::Dependency,
+                JsonNodeKind::NoVersions,
+                JsonNodeKind::Derived
+            ]
+        );
+        assert_eq!(report.nodes[2].causes, vec![0, 1]);
+        assert_eq!(
+            report.nodes[0].terms[1],
+            JsonTerm {
+                package: "A".to_string(),
+                constraint: "A >= 1.0 & < 2.0".to_string(),
+                negated: true,
+            }
+        );
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["nodes"][1]["kind"], "no-versions");
+        assert_eq!(
+            json["nodes"][2]["description"],
+            "the request can't be satisfied"
+        );
+        assert!(json["nodes"][0]["origin"].is_null());
+    }
+
+    #[test]
+    fn test_json_report_shared() {
+        let shared = match no_versions_tree() {
+            DerivationTree::Derived(derived) => Arc::new(DerivationTree::Derived(Derived {
+                shared_id: Some(0),
+                ..derived
+            })),
+            DerivationTree::External(_) => unreachable!(),
+        };
+        let tree = DerivationTree::Derived(Derived {
+            terms: Map::default(),
+            shared_id: None,
+            cause1: shared.clone(),
+            cause2: shared,
+        });
+        let report = OpamReportFormatter::default().json_report(&tree).unwrap();
+        assert_eq!(report.nodes.len(), 4);
+        assert_eq!(report.nodes[report.root].causes, vec![2, 2]);
+    }
+
+    #[test]
+    fn test_opam_file() {
+        let solver = Index::new("./example-repo/packages".to_string()).solver();
+        let formatter = OpamReportFormatter::new(&solver);
+        let external = External::FromDependencyOf(
+            Package::Base("F".to_string()),
+            Range::singleton(version("2.0.0")),
+            Package::Base("D".to_string()),
+            Range::higher_than(version("2.0.0")).intersection(&installed_range()),
+        );
+        let formatted = formatter.format_external(&external);
+        assert!(formatted.starts_with("F = 2.0.0 depends on D >= 2.0.0"));
+        // the origin is the file that was read, which for F is its opam.json
+        assert!(formatted.ends_with("F/F.2.0.0/opam.json)"));
+
+        let report = formatter.json_report(&DerivationTree::External(external)).unwrap();
+        let origin = report.nodes[0].origin.as_ref().unwrap();
+        assert_eq!(
+            (origin.package.as_str(), origin.version.as_str()),
+            ("F", "2.0.0")
+        );
+        assert!(origin.file.ends_with("F/F.2.0.0/opam.json"));
+    }
+}